@@ -1,5 +1,6 @@
 #!/usr/bin/env python3
 
+import gzip
 import http
 import os
 from http.server import BaseHTTPRequestHandler
@@ -23,6 +24,8 @@ class SimpleHandler(BaseHTTPRequestHandler):
 
 		length = int(self.headers['Content-Length'] or 0)
 		body = self.rfile.read(length)
+		if self.headers['Content-Encoding'] == 'gzip':
+			body = gzip.decompress(body)
 		print(str(body, encoding='utf-8'))
 		print("================================")
 
@@ -1,88 +1,200 @@
-use std::io::Read;
+use std::future::Future;
 use std::io::Result;
-use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
 
 use actix_web::web;
 use actix_web::HttpResponse;
-use serde::Deserialize;
-use serde::Serialize;
+use notify::RecommendedWatcher;
 use tokio::signal::unix::SignalKind;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio_cron_scheduler::Job;
 use tokio_cron_scheduler::JobScheduler;
 use tokio_cron_scheduler::JobSchedulerError;
+use uuid::Uuid;
 
+use crate::channel::Channel;
 use crate::collect;
+use crate::collect::outbox;
+use crate::collect::outbox::Outbox;
+use crate::collect::outbox::Outboxes;
+use crate::collect::sink::Delivery;
+use crate::collect::state::ReportState;
+use crate::collect::task::Reporter;
+use crate::command::Commander;
+use crate::config;
+use crate::config::discover::Discoverer;
+use crate::config::layer::Loader;
+use crate::config::Config;
+use crate::config::Every;
+use crate::config::JobConfig;
+use crate::schema::AgentState;
+
+/// Quiet period to wait for after a file change, since editors tend to write a file in steps.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The reporter currently in use, swapped as a whole when the configuration is reloaded.
+pub(crate) type SharedReporter = Arc<RwLock<Arc<Reporter>>>;
 
 pub struct Agent {
-  config: Config,
-  state:  AgentState,
+  loader:    Loader,
+  config:    Arc<Config>,
+  outboxes:  Outboxes,
+  reports:   Arc<ReportState>,
+  reporter:  SharedReporter,
+  scheduler: Option<JobScheduler>,
+  jobs:      Vec<Uuid>,
+  /// Asks the reloader to reload the configuration for the given reason, once it runs.
+  reload:    Option<UnboundedSender<String>>,
 }
 
 impl Agent {
-  pub fn new<P>(config: P) -> Self
-  where
-    P: AsRef<Path>,
-  {
-    let mut file = std::fs::OpenOptions::new()
-      .create(false)
-      .write(false)
-      .read(true)
-      .open(config.as_ref())
-      .unwrap_or_else(|e| panic!("Path to config file: {}", e.to_string()));
-
-    let mut buff = String::new();
-    let _ = file.read_to_string(&mut buff);
-    let config: Config = toml::from_str(buff.as_str())
-      .unwrap_or_else(|e| panic!("Failed to parse config file as TOML: {}", e.to_string()));
-
-    Self {
+  pub fn new(loader: Loader) -> Result<Self> {
+    let layered = loader.load()?;
+    log::debug!("Effective configuration:\n{}", layered.render());
+
+    let config = Arc::new(layered.config().clone());
+    let outboxes = outbox::open_all(&config)?;
+    let reports = Arc::new(ReportState::load(config.state_dir())?);
+    let reporter = Reporter::new(config.clone(), outboxes.clone(), reports.clone())?;
+
+    Ok(Self {
+      loader,
       config,
-      state: AgentState::Ready,
-    }
+      outboxes,
+      reports,
+      reporter: Arc::new(RwLock::new(Arc::new(reporter))),
+      scheduler: None,
+      jobs: vec![],
+      reload: None,
+    })
   }
 
   pub async fn start(&mut self) -> Result<()> {
-    self.state = AgentState::Start;
+    self.reports.set_agent_state(AgentState::Start);
 
     let _ = self.start_scheduler().await;
+    self.start_replayer();
+    let _ = self.start_reloader();
+    self.start_discoverer();
+    self.start_commander();
+    self.start_channel();
     let _ = self.start_webserver().await;
+    // The time of the last delivery is saved only along with the rest of the state otherwise.
+    self.reports.save().await;
 
     Ok(())
   }
 
   async fn start_scheduler(&mut self) -> std::result::Result<(), JobSchedulerError> {
     let scheduler = JobScheduler::new().await?;
-    scheduler
-      .add(Job::new_async("*/5 * * * * *", |_uuid, _lock| {
-        Box::pin(async { collect::task::report_heartbeat().await })
-      })?)
-      .await?;
-    scheduler
-      .add(Job::new_async("*/5 * * * * *", |_uuid, _lock| {
-        Box::pin(async { collect::task::report_machine_info().await })
-      })?)
-      .await?;
+
+    for job in build_jobs(&self.config, &self.reporter, &self.reports)? {
+      self.jobs.push(scheduler.add(job).await?);
+    }
 
     scheduler.start().await?;
+    run_at_startup(&self.config, &self.reporter, &self.reports);
 
     scheduler.shutdown_on_signal(SignalKind::terminate());
     scheduler.shutdown_on_signal(SignalKind::interrupt());
     scheduler.shutdown_on_signal(SignalKind::quit());
 
     log::info!("The scheduler is starting by the agent.");
+    self.scheduler = Some(scheduler);
+
+    Ok(())
+  }
+
+  /// Replay the reports queued in each outbox, in the background.
+  fn start_replayer(&mut self) {
+    for (route, outbox) in &self.outboxes {
+      tokio::spawn(replay(route.clone(), outbox.clone(), self.reporter.clone()));
+    }
+    if !self.outboxes.is_empty() {
+      log::info!("The outbox replayer is starting by the agent.");
+    }
+  }
+
+  /// Reload the configuration on SIGHUP, or when the configuration files change.
+  fn start_reloader(&mut self) -> Result<()> {
+    let scheduler = match &self.scheduler {
+      Some(scheduler) => scheduler.clone(),
+      None => return Ok(()),
+    };
+
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let (reload, requests) = tokio::sync::mpsc::unbounded_channel();
+    let watcher = config::watch::watch(&self.loader, sender)
+      .map_err(|e| log::warn!("Configuration files are not watched: {}", e))
+      .ok();
+    let hangup = tokio::signal::unix::signal(SignalKind::hangup())?;
 
+    let reloader = Reloader {
+      loader: self.loader.clone(),
+      config: self.config.clone(),
+      outboxes: self.outboxes.clone(),
+      reports: self.reports.clone(),
+      reporter: self.reporter.clone(),
+      scheduler,
+      jobs: std::mem::take(&mut self.jobs),
+    };
+    tokio::spawn(reloader.run(watcher, receiver, hangup, requests));
+    self.reload = Some(reload);
+
+    log::info!("The configuration reloader is starting by the agent.");
     Ok(())
   }
 
+  /// Discover the CMDB server again periodically, reloading the configuration when it moves.
+  fn start_discoverer(&mut self) {
+    let reload = match &self.reload {
+      Some(reload) if !self.config.discovery().sources().is_empty() => reload.clone(),
+      _ => return,
+    };
+    tokio::spawn(Discoverer::new(self.loader.clone()).run(reload));
+    log::info!("The discoverer is starting by the agent.");
+  }
+
+  /// Run the commands the server answers the heartbeats with, in the background.
+  fn start_commander(&mut self) {
+    let commander = Commander::new(
+      self.loader.clone(),
+      self.reports.clone(),
+      self.reporter.clone(),
+      self.reload.clone(),
+    );
+    tokio::spawn(commander.run());
+    log::info!("The commander is starting by the agent.");
+  }
+
+  /// Keep the channel to the CMDB server open in the background, if enabled.
+  fn start_channel(&mut self) {
+    if !*self.config.channel().enabled() {
+      return;
+    }
+    tokio::spawn(Channel::new(self.reports.clone(), self.reporter.clone()).run());
+    log::info!("The channel is starting by the agent.");
+  }
+
   async fn start_webserver(&mut self) -> Result<()> {
     log::info!("The web server is starting by the agent.");
 
-    actix_web::HttpServer::new(|| {
+    let reports = web::Data::from(self.reports.clone());
+    actix_web::HttpServer::new(move || {
       actix_web::App::new()
+        .app_data(reports.clone())
         .service(crate::web::health_handler)
+        .service(crate::web::targets_handler)
+        .service(crate::web::jobs_handler)
         .default_service(web::to(HttpResponse::NotFound))
     })
-    .bind(("0.0.0.0", 8080))?
+    .bind(self.config.addr())?
     .run()
     .await?;
 
@@ -90,12 +202,292 @@ impl Agent {
   }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Config {}
+/// A run of a job with the reporter current when it starts.
+type Run = fn(Arc<Reporter>) -> Pin<Box<dyn Future<Output = ()> + Send>>;
 
-#[derive(Default)]
-pub enum AgentState {
-  #[default]
-  Ready,
-  Start,
+/// The jobs of the agent by name, along with their schedules.
+fn jobs(config: &Config) -> [(&'static str, &JobConfig, Run); 2] {
+  let schedule = config.schedule();
+  [
+    ("heartbeat", schedule.heartbeat(), |reporter| {
+      Box::pin(async move { reporter.report_heartbeat().await })
+    }),
+    ("inventory", schedule.inventory(), |reporter| {
+      Box::pin(async move { reporter.report_machine_info().await })
+    }),
+  ]
+}
+
+/// Build the scheduled jobs, each of them picking the current reporter at every run.
+fn build_jobs(
+  config: &Config,
+  reporter: &SharedReporter,
+  reports: &Arc<ReportState>,
+) -> std::result::Result<Vec<Job>, JobSchedulerError> {
+  let host = collect::host::get_hostname().unwrap_or_default();
+  let mut built = vec![];
+  for (name, job, run) in jobs(config) {
+    let delay = job.delay(&host, name);
+    if !delay.is_zero() {
+      log::debug!("Runs of the {} job are delayed by {:?}", name, delay);
+    }
+
+    let (job_config, reporter, reports) = (job.clone(), reporter.clone(), reports.clone());
+    let task = move |_uuid, _lock| -> Pin<Box<dyn Future<Output = ()> + Send>> {
+      let (job, reporter, reports) = (job_config.clone(), reporter.clone(), reports.clone());
+      Box::pin(async move {
+        run_job(name, &job, delay, run, &reporter, &reports).await;
+      })
+    };
+    let every = job.every().parse().map_err(|e| {
+      log::error!("Invalid schedule of the {} job: {}", name, e);
+      JobSchedulerError::ParseSchedule
+    })?;
+    built.push(match every {
+      Every::Interval(interval) => Job::new_repeated_async(interval, task)?,
+      Every::Cron(expression) => Job::new_async(expression.as_str(), task)?,
+    });
+  }
+  Ok(built)
+}
+
+/// Run the jobs to be run at startup once, after the delay of the host.
+fn run_at_startup(config: &Config, reporter: &SharedReporter, reports: &Arc<ReportState>) {
+  let host = collect::host::get_hostname().unwrap_or_default();
+  for (name, job, run) in jobs(config) {
+    if !*job.run_at_startup() {
+      continue;
+    }
+    let delay = job.delay(&host, name);
+    let (job, reporter, reports) = (job.clone(), reporter.clone(), reports.clone());
+    tokio::spawn(async move {
+      log::info!("Run the {} job at startup", name);
+      run_job(name, &job, delay, run, &reporter, &reports).await;
+    });
+  }
+}
+
+/// Run the job now, without the delay of the host, as its scheduled runs do otherwise: skipped or
+/// queued while the previous one is still going, and given up on after its timeout. Tells whether
+/// it ran.
+pub(crate) async fn run_job_now(
+  name: &str,
+  reporter: &SharedReporter,
+  reports: &ReportState,
+) -> bool {
+  let current = reporter.read().unwrap().clone();
+  let Some((name, job, run)) = jobs(current.config()).into_iter().find(|(job, ..)| *job == name)
+  else {
+    return false;
+  };
+  run_job(name, job, Duration::ZERO, run, reporter, reports).await
+}
+
+/// Run the job with the current reporter after the delay of the host, unless its previous run is
+/// still going, and give up on it after its timeout. Tells whether it ran.
+///
+/// The delay counts as part of the run, so that runs waiting for it do not pile up when the splay
+/// is longer than the schedule, nor run twice when a reload reschedules the job meanwhile.
+async fn run_job(
+  name: &str,
+  job: &JobConfig,
+  delay: Duration,
+  run: Run,
+  reporter: &SharedReporter,
+  reports: &ReportState,
+) -> bool {
+  let Some(_running) = reports.start_job(name, *job.overlap()).await else {
+    log::warn!(
+      "Skipped a run of the {} job, the previous one is still going",
+      name
+    );
+    return false;
+  };
+  tokio::time::sleep(delay).await;
+
+  let started = Instant::now();
+  let current = reporter.read().unwrap().clone();
+  let timed_out = tokio::time::timeout(*job.timeout(), run(current)).await.is_err();
+  if timed_out {
+    log::error!(
+      "Gave up on the run of the {} job after {:?}",
+      name,
+      job.timeout()
+    );
+  }
+  reports.finish_job(name, started.elapsed(), timed_out);
+  true
+}
+
+/// Replay the reports queued along the route in order with the current reporter, backing off while
+/// the server is unavailable.
+async fn replay(route: Option<String>, outbox: Arc<Outbox>, reporter: SharedReporter) {
+  let mut backoff = None;
+  loop {
+    let reporter = reporter.read().unwrap().clone();
+    let config = reporter.config().outbox().clone();
+    let Some((sequence, entry)) = outbox.front(&config).await else {
+      backoff = None;
+      outbox.pushed().await;
+      continue;
+    };
+
+    let (target, delivery) = reporter.deliver(route.as_deref(), &entry).await;
+    if delivery.is_transient() {
+      let delay = backoff.map_or(*config.backoff(), |delay: Duration| {
+        (delay * 2).min(*config.max_backoff())
+      });
+      log::warn!(
+        "Target {} is unavailable, replay the {} queued report(s) in {:?}",
+        target,
+        outbox.len(),
+        delay
+      );
+      backoff = Some(delay);
+      tokio::time::sleep(delay).await;
+      continue;
+    }
+
+    outbox.remove(sequence).await;
+    backoff = None;
+    match delivery {
+      Delivery::Delivered => log::info!(
+        "Success to replay the {} queued {:?} ago to {}, {} report(s) left",
+        entry.kind,
+        entry.age(),
+        target,
+        outbox.len()
+      ),
+      delivery => log::error!(
+        "Dropped the queued {}, which target {} does not take: {}",
+        entry.kind,
+        target,
+        delivery
+      ),
+    }
+  }
+}
+
+struct Reloader {
+  loader:    Loader,
+  config:    Arc<Config>,
+  outboxes:  Outboxes,
+  reports:   Arc<ReportState>,
+  reporter:  SharedReporter,
+  scheduler: JobScheduler,
+  jobs:      Vec<Uuid>,
+}
+
+impl Reloader {
+  async fn run(
+    mut self,
+    _watcher: Option<RecommendedWatcher>,
+    mut changes: UnboundedReceiver<PathBuf>,
+    mut hangup: tokio::signal::unix::Signal,
+    mut requests: UnboundedReceiver<String>,
+  ) {
+    loop {
+      tokio::select! {
+        Some(()) = hangup.recv() => {
+          log::info!("Received SIGHUP, reloading the configuration.");
+        }
+        Some(path) = changes.recv() => {
+          tokio::time::sleep(RELOAD_DEBOUNCE).await;
+          while changes.try_recv().is_ok() {}
+          log::info!("Configuration file {} changed, reloading the configuration.", path.display());
+        }
+        Some(reason) = requests.recv() => {
+          log::info!("{}, reloading the configuration.", reason);
+        }
+        else => break,
+      }
+
+      self.reload().await;
+    }
+  }
+
+  /// Load and validate the new configuration, then swap the reporter and reschedule the jobs.
+  ///
+  /// Runs in progress keep the reporter they started with. An invalid configuration is rejected as
+  /// a whole, leaving the current one in place.
+  async fn reload(&mut self) {
+    let layered = match self.loader.load() {
+      Ok(layered) => layered,
+      Err(e) => {
+        log::error!(
+          "Rejected the new configuration, keep running the current one. {}",
+          e
+        );
+        return;
+      }
+    };
+    let config = Arc::new(layered.config().clone());
+
+    let reporter = match Reporter::new(config.clone(), self.outboxes.clone(), self.reports.clone())
+    {
+      Ok(reporter) => reporter,
+      Err(e) => {
+        log::error!(
+          "Rejected the new configuration, keep running the current one: {}",
+          e
+        );
+        return;
+      }
+    };
+    let jobs = match build_jobs(&config, &self.reporter, &self.reports) {
+      Ok(jobs) => jobs,
+      Err(e) => {
+        log::error!(
+          "Rejected the new configuration, keep running the current one: {}",
+          e
+        );
+        return;
+      }
+    };
+
+    if config.addr() != self.config.addr() {
+      log::warn!(
+        "Changing the listen address requires restarting the agent, keep listening on {}.",
+        self.config.addr()
+      );
+    }
+    if config.state_dir() != self.config.state_dir()
+      || config.outbox().enabled() != self.config.outbox().enabled()
+      || outbox::routes(&config) != outbox::routes(&self.config)
+    {
+      log::warn!(
+        "Moving or toggling the outbox, or changing the routes of the reports, requires \
+         restarting the agent, keep the current outboxes."
+      );
+    }
+
+    if config.channel().enabled() != self.config.channel().enabled() {
+      log::warn!(
+        "Opening or closing the channel requires restarting the agent, keep it {}.",
+        if *self.config.channel().enabled() {
+          "open"
+        } else {
+          "closed"
+        }
+      );
+    }
+
+    *self.reporter.write().unwrap() = Arc::new(reporter);
+
+    for id in self.jobs.drain(..) {
+      if let Err(e) = self.scheduler.remove(&id).await {
+        log::error!("Failed to unschedule job {}: {}", id, e);
+      }
+    }
+    for job in jobs {
+      match self.scheduler.add(job).await {
+        Ok(id) => self.jobs.push(id),
+        Err(e) => log::error!("Failed to schedule job: {}", e),
+      }
+    }
+
+    self.config = config;
+    log::info!("The configuration is reloaded.");
+    log::debug!("Effective configuration:\n{}", layered.render());
+  }
 }
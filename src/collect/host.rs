@@ -9,9 +9,9 @@ use std::io::Result;
 use std::marker::PhantomData;
 
 use crate::support::kube::is_under_kubernetes;
+use crate::support::kube::KUBERNETES_NODE_NAME_KEY;
 
 const POSIX_HOST_NAME_MAX: libc::c_long = 255;
-const KUBERNETES_NODE_NAME_KEY: &str = "K8S_NODE_NAME";
 
 /// Get hostname
 /// - In kubernetes, read environment variables, which is injected from `spec.nodeName` if in
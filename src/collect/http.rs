@@ -1,69 +1,665 @@
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use hmac::Mac;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderValue;
+use reqwest::header::ACCEPT_ENCODING;
+use reqwest::header::AUTHORIZATION;
+use reqwest::header::CONTENT_ENCODING;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::RETRY_AFTER;
 use reqwest::Request;
 use reqwest::Response;
+use reqwest::StatusCode;
 use reqwest_middleware::ClientBuilder;
 use reqwest_middleware::ClientWithMiddleware;
 use reqwest_middleware::Middleware;
 use reqwest_middleware::Next;
 use reqwest_middleware::Result;
-use reqwest_retry::policies::ExponentialBackoff;
-use reqwest_retry::RetryTransientMiddleware;
+use sha2::Digest;
 use task_local_extensions::Extensions;
+use uuid::Uuid;
+
+use crate::collect::tls;
+use crate::config::Compression;
+use crate::config::CompressionConfig;
+use crate::config::Credentials;
+use crate::config::HttpConfig;
+use crate::config::ProxyConfig;
+use crate::config::SigningAlgorithm;
+use crate::config::SigningConfig;
+use crate::config::SigningKey;
+use crate::identity::Identity;
+
+const USER_AGENT: &str = "CMDB Agent/reqwest client";
+
+/// Build the client of a target, sending the given credentials along with every request.
+pub fn default_client(
+  config: &HttpConfig,
+  credentials: Option<&Credentials>,
+  signing: &SigningConfig,
+  identity: Option<Arc<Identity>>,
+) -> std::io::Result<ClientWithMiddleware> {
+  let mut headers = HeaderMap::new();
+  headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+  if let Some(credentials) = credentials {
+    headers.insert(AUTHORIZATION, authorization(credentials)?);
+  }
+
+  let mut client = reqwest::Client::builder()
+    .user_agent(USER_AGENT)
+    .timeout(*config.timeout())
+    .connect_timeout(*config.connect_timeout())
+    .default_headers(headers);
+  if let Some(tls) = config.tls() {
+    client = client.use_preconfigured_tls(tls::client_config(tls)?);
+  }
+  if let Some(proxy) = config.proxy() {
+    client = client.proxy(self::proxy(proxy)?);
+  }
+  let client = client.build().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+  let mut builder = ClientBuilder::new(client);
+  // Compression comes before signing, so that the signature covers the bytes on the wire.
+  if let Some(compression) = CompressionMiddleware::new(config.compression()) {
+    builder = builder.with(compression);
+  }
+  builder = builder.with(RetryMiddleware::new(config));
+  // Signing comes after retrying, so that each attempt is signed with a fresh nonce.
+  if let Some(signer) = Signer::new(signing, identity) {
+    builder = builder.with(SignatureMiddleware::new(signer));
+  }
 
-pub fn default_client() -> ClientWithMiddleware {
-  let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-  ClientBuilder::new(reqwest::Client::new())
-    .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-    .build()
+  Ok(builder.build())
 }
 
-pub struct SignatureMiddleware;
+/// Send the request to each endpoint in order, until one of them answers.
+pub(crate) async fn send<F>(urls: &[String], request: F) -> Result<Response>
+where
+  F: Fn(&str) -> reqwest_middleware::RequestBuilder,
+{
+  let mut result = Err(reqwest_middleware::Error::middleware(std::io::Error::new(
+    std::io::ErrorKind::NotFound,
+    "No CMDB server endpoint is configured",
+  )));
 
-impl SignatureMiddleware {
-  const SIGNATURE_HEADER_KEY: &'static str = "X-CMDB-Signature";
+  for url in urls {
+    result = request(url).send().await;
+    match &result {
+      Ok(_) => break,
+      Err(e) => log::warn!("CMDB server endpoint {} is unreachable: {}", url, e),
+    }
+  }
+
+  result
+}
+
+/// Whether the server may take the request if it is sent again later.
+pub(crate) fn is_transient(status: StatusCode) -> bool {
+  status.is_server_error()
+    || status == StatusCode::TOO_MANY_REQUESTS
+    || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// How long the server asks to wait by the `Retry-After` header of a `429 Too Many Requests` or
+/// `503 Service Unavailable`, given in seconds or as an HTTP date.
+pub(crate) fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+  if !matches!(
+    status,
+    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+  ) {
+    return None;
+  }
+  let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+  match value.parse::<u64>() {
+    Ok(seconds) => Some(Duration::from_secs(seconds)),
+    Err(_) => {
+      let date = httpdate::parse_http_date(value).ok()?;
+      Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+  }
+}
+
+/// Build the proxy of every request, which replaces the proxies from the environment variables.
+fn proxy(config: &ProxyConfig) -> std::io::Result<reqwest::Proxy> {
+  let mut proxy = reqwest::Proxy::all(config.url())
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+  if let Some(username) = config.username() {
+    let password = config.password().as_ref().map(|password| password.expose()).transpose()?;
+    proxy = proxy.basic_auth(username, &password.unwrap_or_default());
+  }
+  Ok(proxy.no_proxy(reqwest::NoProxy::from_string(&config.no_proxy().join(","))))
+}
+
+/// Build the `Authorization` header from the configured credentials.
+fn authorization(credentials: &Credentials) -> std::io::Result<HeaderValue> {
+  let value = match credentials {
+    Credentials::Basic { username, password } => {
+      let password = password.as_ref().map(|password| password.expose()).transpose()?;
+      let pair = format!("{}:{}", username, password.unwrap_or_default());
+      format!("Basic {}", STANDARD.encode(pair))
+    }
+    Credentials::Bearer { token } => format!("Bearer {}", token.expose()?),
+  };
+
+  let mut value = HeaderValue::from_str(&value)
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+  value.set_sensitive(true);
+  Ok(value)
+}
+
+/// Sends the request again when it fails on the way, or when the server answers with a transient
+/// status, up to `max_retries` times.
+///
+/// The delay doubles from `retry_backoff` up to `max_retry_backoff` on every retry, less up to half
+/// of it at random so that the agents do not retry all at once. A server answering `429` or `503`
+/// tells how long to wait by `Retry-After` instead: a longer wait than `max_retry_backoff` is not
+/// retried, the response being left for the circuit breaker of the target.
+pub struct RetryMiddleware {
+  max_retries: u32,
+  backoff:     Duration,
+  max_backoff: Duration,
+}
+
+impl RetryMiddleware {
+  pub fn new(config: &HttpConfig) -> Self {
+    Self {
+      max_retries: *config.max_retries(),
+      backoff:     *config.retry_backoff(),
+      max_backoff: *config.max_retry_backoff(),
+    }
+  }
 
-  fn sign(bytes: &[u8], secret_key: &[u8]) -> Result<String> {
-    let mut mac: hmac::Hmac<sha1::Sha1> = hmac::Mac::new_from_slice(secret_key)
-      .unwrap_or_else(|e| panic!("HMAC can not take key of any size: {}", e));
-    mac.update(bytes);
-    let result = mac.finalize();
-    Ok(hex::encode(result.into_bytes()))
+  /// The delay before the given retry, counted from zero, before the jitter.
+  fn backoff(&self, retry: u32) -> Duration {
+    self.backoff.saturating_mul(2u32.saturating_pow(retry)).min(self.max_backoff)
   }
 }
 
 #[async_trait::async_trait]
-impl Middleware for SignatureMiddleware {
+impl Middleware for RetryMiddleware {
+  async fn handle(
+    &self,
+    request: Request,
+    extensions: &mut Extensions,
+    next: Next<'_>,
+  ) -> Result<Response> {
+    let mut retry = 0;
+    loop {
+      // A streamed body can not be sent twice.
+      let Some(attempt) = request.try_clone() else {
+        return next.run(request, extensions).await;
+      };
+      let result = next.clone().run(attempt, extensions).await;
+
+      let jittered = || {
+        let delay = self.backoff(retry);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+      };
+      let delay = match &result {
+        Ok(response) if is_transient(response.status()) => {
+          retry_after(response.status(), response.headers()).unwrap_or_else(jittered)
+        }
+        Err(reqwest_middleware::Error::Reqwest(e))
+          if e.is_connect() || e.is_timeout() || e.is_request() =>
+        {
+          jittered()
+        }
+        _ => return result,
+      };
+      if retry >= self.max_retries || delay > self.max_backoff {
+        return result;
+      }
+
+      retry += 1;
+      log::debug!(
+        "Retry {} in {:?}, {} of {}",
+        request.url(),
+        delay,
+        retry,
+        self.max_retries
+      );
+      tokio::time::sleep(delay).await;
+    }
+  }
+}
+
+/// Compresses the request bodies of at least `min_bytes` with the configured algorithm.
+///
+/// The server tells which encodings it supports by the `Accept-Encoding` header of its responses,
+/// as in RFC 7694. A server which does not know about it answers 415 Unsupported Media Type to a
+/// compressed body: the request is sent again uncompressed, and so are the next ones.
+pub struct CompressionMiddleware {
+  algorithm: Compression,
+  min_bytes: usize,
+  /// Whether the server is believed to accept the encoding.
+  accepted:  AtomicBool,
+}
+
+impl CompressionMiddleware {
+  pub fn new(config: &CompressionConfig) -> Option<Self> {
+    let encoding = Self::encoding(*config.algorithm())?;
+    log::debug!("Compress request bodies with {}", encoding);
+    Some(Self {
+      algorithm: *config.algorithm(),
+      min_bytes: *config.min_bytes(),
+      accepted:  AtomicBool::new(true),
+    })
+  }
+
+  fn encoding(algorithm: Compression) -> Option<&'static str> {
+    match algorithm {
+      Compression::None => None,
+      Compression::Gzip => Some("gzip"),
+      Compression::Zstd => Some("zstd"),
+    }
+  }
+
+  fn compress(algorithm: Compression, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    match algorithm {
+      Compression::None => Ok(bytes.to_vec()),
+      Compression::Gzip => {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(bytes)?;
+        encoder.finish()
+      }
+      Compression::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+  }
+
+  /// Follow the encodings the server advertises, if it does.
+  fn negotiate(&self, encoding: &str, response: &Response) {
+    let Some(accepted) = response.headers().get(ACCEPT_ENCODING) else {
+      return;
+    };
+    let accepted = accepted.to_str().unwrap_or_default().split(',').any(|coding| {
+      let coding = coding.split(';').next().unwrap_or_default().trim();
+      coding.eq_ignore_ascii_case(encoding) || coding == "*"
+    });
+    if self.accepted.swap(accepted, Ordering::Relaxed) != accepted {
+      log::info!(
+        "CMDB server {} {} request bodies",
+        if accepted {
+          "accepts"
+        } else {
+          "does not accept"
+        },
+        encoding
+      );
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl Middleware for CompressionMiddleware {
   async fn handle(
     &self,
     mut request: Request,
     extensions: &mut Extensions,
     next: Next<'_>,
   ) -> Result<Response> {
-    let body = request.body();
+    let encoding = Self::encoding(self.algorithm).unwrap_or_default();
+    let size = request.body().and_then(|body| body.as_bytes()).map(<[u8]>::len);
+    let compress = self.accepted.load(Ordering::Relaxed)
+      && size.is_some_and(|size| size >= self.min_bytes)
+      && !request.headers().contains_key(CONTENT_ENCODING);
+    if !compress {
+      let response = next.run(request, extensions).await?;
+      self.negotiate(encoding, &response);
+      return Ok(response);
+    }
 
-    if let Some(body) = body {
-      let signature = Self::sign(
-        body.as_bytes().unwrap(),
-        "b35057912c5def0e848b14fb".as_bytes(),
-      )?;
+    let plain = request.try_clone();
+    let bytes = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+    let compressed =
+      Self::compress(self.algorithm, bytes).map_err(reqwest_middleware::Error::middleware)?;
+    *request.body_mut() = Some(compressed.into());
+    request
+      .headers_mut()
+      .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+
+    let response = next.clone().run(request, extensions).await?;
+    match plain {
+      Some(plain) if response.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+        log::warn!(
+          "CMDB server does not accept {} request bodies, send them uncompressed",
+          encoding
+        );
+        self.accepted.store(false, Ordering::Relaxed);
+        let response = next.run(plain, extensions).await?;
+        self.negotiate(encoding, &response);
+        Ok(response)
+      }
+      _ => {
+        self.negotiate(encoding, &response);
+        Ok(response)
+      }
+    }
+  }
+}
+
+/// Signs requests and messages with HMAC, naming the key by the `X-CMDB-Key-Id` header, and with
+/// the Ed25519 key of the agent in the `X-CMDB-Agent-Signature` header.
+///
+/// The signed string covers the method, the path with query, the timestamp, the nonce and the
+/// SHA-256 digest of the body, one per line, so that the server can reject replayed or altered
+/// requests:
+///
+/// ```text
+/// POST
+/// /v1/heartbeat
+/// 1704067200
+/// 6f1c1f8ab5a64e5c9d8c0e3f3b0e9a52
+/// e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+/// ```
+///
+/// Messages published to a broker are signed the same way, with `PUBLISH` as the method and the
+/// topic or subject as the path.
+pub struct Signer {
+  algorithm: SigningAlgorithm,
+  active:    Option<SigningKey>,
+  previous:  Option<SigningKey>,
+  identity:  Option<Arc<Identity>>,
+}
+
+impl Signer {
+  const AGENT_SIGNATURE_HEADER_KEY: &'static str = "X-CMDB-Agent-Signature";
+  const KEY_ID_HEADER_KEY: &'static str = "X-CMDB-Key-Id";
+  const NONCE_HEADER_KEY: &'static str = "X-CMDB-Nonce";
+  const SIGNATURE_HEADER_KEY: &'static str = "X-CMDB-Signature";
+  const TIMESTAMP_HEADER_KEY: &'static str = "X-CMDB-Timestamp";
+
+  /// Sign with the configured keys, if there is an active one or an agent key pair.
+  pub fn new(config: &SigningConfig, identity: Option<Arc<Identity>>) -> Option<Self> {
+    if config.active().is_none() && identity.is_none() {
+      return None;
+    }
+    Some(Self {
+      algorithm: *config.algorithm(),
+      active: config.active().clone(),
+      previous: config.previous().clone(),
+      identity,
+    })
+  }
+
+  pub fn active(&self) -> Option<&SigningKey> {
+    self.active.as_ref()
+  }
+
+  fn sign(algorithm: SigningAlgorithm, bytes: &[u8], secret_key: &[u8]) -> String {
+    let result = match algorithm {
+      SigningAlgorithm::HmacSha1 => {
+        let mut mac: hmac::Hmac<sha1::Sha1> = hmac::Mac::new_from_slice(secret_key)
+          .unwrap_or_else(|e| panic!("HMAC can not take key of any size: {}", e));
+        mac.update(bytes);
+        mac.finalize().into_bytes().to_vec()
+      }
+      SigningAlgorithm::HmacSha256 => {
+        let mut mac: hmac::Hmac<sha2::Sha256> = hmac::Mac::new_from_slice(secret_key)
+          .unwrap_or_else(|e| panic!("HMAC can not take key of any size: {}", e));
+        mac.update(bytes);
+        mac.finalize().into_bytes().to_vec()
+      }
+    };
+    hex::encode(result)
+  }
+
+  fn canonical_string(
+    method: &str,
+    path: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+  ) -> String {
+    format!(
+      "{}\n{}\n{}\n{}\n{}",
+      method,
+      path,
+      timestamp,
+      nonce,
+      hex::encode(sha2::Sha256::digest(body))
+    )
+  }
+
+  /// The HMAC signature of the bytes as in the `X-CMDB-Signature` header, e.g. `SHA256=<hex>`.
+  fn signature(&self, bytes: &[u8], secret_key: &[u8]) -> String {
+    let scheme = match self.algorithm {
+      SigningAlgorithm::HmacSha1 => "SHA1",
+      SigningAlgorithm::HmacSha256 => "SHA256",
+    };
+    format!(
+      "{}={}",
+      scheme,
+      Self::sign(self.algorithm, bytes, secret_key)
+    )
+  }
+
+  /// Whether the signature, as in the `X-CMDB-Signature` header, is the one of the bytes by the
+  /// active or the previous key with the given ID.
+  pub fn verify(&self, key_id: &str, bytes: &[u8], signature: &str) -> bool {
+    let key = [&self.active, &self.previous]
+      .into_iter()
+      .flatten()
+      .find(|key| key.id() == key_id);
+    let Some(key) = key else {
+      return false;
+    };
+    let secret = match key.secret().expose() {
+      Ok(secret) => secret,
+      Err(e) => {
+        log::warn!("Failed to read the signing key {}: {}", key_id, e);
+        return false;
+      }
+    };
+
+    // Compared in constant time, so as not to tell how much of a forged signature is right.
+    let expected = self.signature(bytes, secret.as_bytes());
+    expected.len() == signature.len()
+      && expected.bytes().zip(signature.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+  }
+
+  /// The headers signing the message with the given key, if any, and the agent key pair.
+  pub fn headers(
+    &self,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    key: Option<&SigningKey>,
+  ) -> std::io::Result<Vec<(&'static str, String)>> {
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|duration| duration.as_secs())
+      .unwrap_or_default();
+    let nonce = Uuid::new_v4().simple().to_string();
+    let canonical = Self::canonical_string(method, path, timestamp, &nonce, body);
+
+    let mut headers = vec![
+      (Self::TIMESTAMP_HEADER_KEY, timestamp.to_string()),
+      (Self::NONCE_HEADER_KEY, nonce),
+    ];
+    if let Some(key) = key {
+      // The secret is resolved on each message, so that a rotated file or variable is picked up.
+      let secret = key.secret().expose()?;
+      let signature = self.signature(canonical.as_bytes(), secret.as_bytes());
+      headers.push((Self::KEY_ID_HEADER_KEY, key.id().to_string()));
+      headers.push((Self::SIGNATURE_HEADER_KEY, signature));
+    }
+    if let Some(identity) = &self.identity {
+      headers.push((
+        Self::AGENT_SIGNATURE_HEADER_KEY,
+        format!("ED25519={}", identity.sign(canonical.as_bytes())),
+      ));
+    }
+
+    Ok(headers)
+  }
+}
+
+/// Signs each request with the [`Signer`]. The request is signed again with the previous key if
+/// the server rejects the active one.
+pub struct SignatureMiddleware {
+  signer: Signer,
+}
+
+impl SignatureMiddleware {
+  pub fn new(signer: Signer) -> Self {
+    Self { signer }
+  }
+
+  fn sign_request(&self, request: &mut Request, key: Option<&SigningKey>) -> Result<()> {
+    let url = request.url();
+    let path = match url.query() {
+      Some(query) => format!("{}?{}", url.path(), query),
+      None => url.path().to_string(),
+    };
+    let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+    let headers = self
+      .signer
+      .headers(request.method().as_str(), &path, body, key)
+      .map_err(reqwest_middleware::Error::middleware)?;
+
+    for (name, value) in headers {
       request.headers_mut().insert(
-        Self::SIGNATURE_HEADER_KEY,
-        format!("SHA1={}", signature)
-          .parse()
-          .map_err(reqwest_middleware::Error::middleware)?,
+        name,
+        value.parse().map_err(reqwest_middleware::Error::middleware)?,
       );
     }
 
-    let headers = request.headers_mut();
-    headers.insert(
-      reqwest::header::USER_AGENT.as_str(),
-      "CMDB Agent/reqwest client".parse().unwrap(),
+    Ok(())
+  }
+}
+
+#[async_trait::async_trait]
+impl Middleware for SignatureMiddleware {
+  async fn handle(
+    &self,
+    mut request: Request,
+    extensions: &mut Extensions,
+    next: Next<'_>,
+  ) -> Result<Response> {
+    let fallback = match (&self.signer.active, &self.signer.previous) {
+      (Some(active), Some(previous)) => {
+        request.try_clone().map(|request| (active, previous, request))
+      }
+      _ => None,
+    };
+
+    self.sign_request(&mut request, self.signer.active())?;
+    let response = next.clone().run(request, extensions).await?;
+
+    match fallback {
+      Some((active, previous, mut request)) if response.status() == StatusCode::UNAUTHORIZED => {
+        log::warn!(
+          "CMDB server rejected signing key {}, retry with the previous key {}",
+          active.id(),
+          previous.id()
+        );
+        self.sign_request(&mut request, Some(previous))?;
+        next.run(request, extensions).await
+      }
+      _ => Ok(response),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sign() {
+    let message = b"The quick brown fox jumps over the lazy dog";
+    assert_eq!(
+      Signer::sign(SigningAlgorithm::HmacSha1, message, b"key"),
+      "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9"
     );
+    assert_eq!(
+      Signer::sign(SigningAlgorithm::HmacSha256, message, b"key"),
+      "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+  }
+
+  #[test]
+  fn test_compress() {
+    let body = br#"{"hostname":"node-01","networks":[]}"#.repeat(64);
+    let gzip = CompressionMiddleware::compress(Compression::Gzip, &body).unwrap();
+    let mut plain = vec![];
+    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gzip[..]), &mut plain).unwrap();
+    assert_eq!(plain, body);
+    assert!(gzip.len() < body.len());
+
+    let zstd = CompressionMiddleware::compress(Compression::Zstd, &body).unwrap();
+    assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), body);
+    assert!(zstd.len() < body.len());
+  }
+
+  #[test]
+  fn test_retry_after() {
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+    assert_eq!(
+      retry_after(StatusCode::TOO_MANY_REQUESTS, &headers),
+      Some(Duration::from_secs(120))
+    );
+    assert_eq!(retry_after(StatusCode::BAD_GATEWAY, &headers), None);
+
     headers.insert(
-      reqwest::header::CONTENT_TYPE.as_str(),
-      "application/json".parse().unwrap(),
+      RETRY_AFTER,
+      HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    assert_eq!(
+      retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers),
+      Some(Duration::ZERO)
     );
+    assert_eq!(
+      retry_after(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new()),
+      None
+    );
+  }
+
+  #[test]
+  fn test_retry_backoff() {
+    let config: HttpConfig =
+      toml::from_str("retry_backoff = \"1s\"\nmax_retry_backoff = \"5s\"").unwrap();
+    let retry = RetryMiddleware::new(&config);
+    assert_eq!(retry.backoff(0), Duration::from_secs(1));
+    assert_eq!(retry.backoff(2), Duration::from_secs(4));
+    assert_eq!(retry.backoff(3), Duration::from_secs(5));
+    assert_eq!(retry.backoff(64), Duration::from_secs(5));
+  }
 
-    next.run(request, extensions).await
+  #[test]
+  fn test_verify() {
+    let config: SigningConfig = toml::from_str(
+      "[active]\nid = \"k2\"\nsecret = \"key\"\n[previous]\nid = \"k1\"\nsecret = \"old\"",
+    )
+    .unwrap();
+    let signer = Signer::new(&config, None).unwrap();
+    let signature = format!(
+      "SHA256={}",
+      Signer::sign(SigningAlgorithm::HmacSha256, b"message", b"key")
+    );
+    assert!(signer.verify("k2", b"message", &signature));
+    assert!(!signer.verify("k1", b"message", &signature));
+    assert!(!signer.verify("k3", b"message", &signature));
+    assert!(!signer.verify("k2", b"altered", &signature));
+  }
+
+  #[test]
+  fn test_canonical_string() {
+    assert_eq!(
+      Signer::canonical_string("POST", "/v1/heartbeat?full=true", 1704067200, "nonce", b""),
+      "POST\n/v1/heartbeat?full=true\n1704067200\nnonce\n\
+       e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
   }
 }
@@ -21,10 +21,21 @@ use crate::schema;
 pub mod host;
 pub mod http;
 pub mod net;
+pub mod outbox;
+pub mod sink;
 pub mod smbios;
+pub mod state;
 pub mod task;
+pub mod tls;
 
 pub fn get_machine_info() -> Result<schema::MachineInfo> {
+  collect_machine_info(&[])
+}
+
+/// Collect the machine info, leaving out the disabled sections, e.g. `networks`, which are left to
+/// their defaults. The hostname and the serial number are always collected.
+pub fn collect_machine_info(disabled: &[String]) -> Result<schema::MachineInfo> {
+  let enabled = |section: &str| !disabled.iter().any(|disabled| disabled == section);
   let mut machine_info = schema::MachineInfo::default();
   let smbios = smbios::get_smbios_data()?;
 
@@ -35,17 +46,28 @@ pub fn get_machine_info() -> Result<schema::MachineInfo> {
   machine_info.set_serial_number(get_serial_number(&smbios)?);
 
   // OS
-  machine_info.set_os(get_os()?);
+  if enabled("os") {
+    machine_info.set_os(get_os()?);
+  }
 
   // Devices
-  machine_info.set_devices(get_devices(&smbios)?);
+  if enabled("devices") {
+    machine_info.set_devices(get_devices(&smbios)?);
+  }
 
   // Network
-  machine_info.set_networks(get_networks()?);
+  if enabled("networks") {
+    machine_info.set_networks(get_networks()?);
+  }
 
   Ok(machine_info)
 }
 
+/// Get the serial number of the system, from SMBIOS.
+pub fn get_system_serial_number() -> Result<String> {
+  get_serial_number(&smbios::get_smbios_data()?)
+}
+
 fn get_serial_number(smbios: &SMBiosData) -> Result<String> {
   Keyword::SystemSerialNumber
     .parse(smbios)
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::config::DeliveryMode;
+use crate::config::OutboxConfig;
+use crate::support::fs::write_private;
+
+/// Name of the outbox directory, in the state directory.
+const OUTBOX_DIR: &str = "outbox";
+
+/// The outboxes by route, see [`routes`].
+pub(crate) type Outboxes = BTreeMap<Option<String>, Arc<Outbox>>;
+
+/// Where the reports are routed to: any target in failover mode, which is `None`, or each target
+/// on its own in fanout mode, so that a target being down does not hold back the others.
+pub(crate) fn routes(config: &Config) -> Vec<Option<String>> {
+  match config.delivery().mode() {
+    DeliveryMode::Failover => vec![None],
+    DeliveryMode::Fanout => {
+      config.targets().into_iter().map(|target| Some(target.name().clone())).collect()
+    }
+  }
+}
+
+/// Open the outbox of every route, if enabled: the outbox directory itself in failover mode, or a
+/// directory per target in it in fanout mode.
+pub(crate) fn open_all(config: &Config) -> Result<Outboxes> {
+  let mut outboxes = Outboxes::new();
+  if !*config.outbox().enabled() {
+    return Ok(outboxes);
+  }
+
+  for route in routes(config) {
+    let mut dir = config.state_dir().join(OUTBOX_DIR);
+    if let Some(target) = &route {
+      dir.push(target);
+    }
+    let outbox = Outbox::open(dir.clone())
+      .map_err(|e| std::io::Error::new(e.kind(), format!("outbox {}: {}", dir.display(), e)))?;
+    outboxes.insert(route, Arc::new(outbox));
+  }
+  Ok(outboxes)
+}
+
+/// What a report is about, which decides where it is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReportKind {
+  Heartbeat,
+  Inventory,
+}
+
+impl ReportKind {
+  /// The name of the kind in topics and records, e.g. `inventory`.
+  pub(crate) fn name(&self) -> &'static str {
+    match self {
+      ReportKind::Heartbeat => "heartbeat",
+      ReportKind::Inventory => "inventory",
+    }
+  }
+}
+
+impl Display for ReportKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ReportKind::Heartbeat => write!(f, "heartbeat"),
+      ReportKind::Inventory => write!(f, "machine info"),
+    }
+  }
+}
+
+/// Identifies a report to its receivers, which can tell the retries and replays of a report from
+/// a new one by its idempotency key, and its order by its sequence number.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReportId {
+  /// Increases with every report of the agent, across restarts.
+  pub(crate) sequence:        u64,
+  pub(crate) idempotency_key: String,
+}
+
+impl ReportId {
+  pub(crate) fn new(sequence: u64) -> Self {
+    Self {
+      sequence,
+      idempotency_key: uuid::Uuid::new_v4().to_string(),
+    }
+  }
+}
+
+/// A report waiting in the outbox.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+  pub(crate) kind:       ReportKind,
+  /// Seconds since the epoch.
+  pub(crate) created_at: u64,
+  /// Reports queued by earlier versions of the agent have none.
+  #[serde(default)]
+  pub(crate) id:         Option<ReportId>,
+  pub(crate) body:       Option<serde_json::Value>,
+}
+
+impl Entry {
+  pub(crate) fn new(kind: ReportKind, id: ReportId, body: Option<serde_json::Value>) -> Self {
+    Self {
+      kind,
+      created_at: now().as_secs(),
+      id: Some(id),
+      body,
+    }
+  }
+
+  pub(crate) fn age(&self) -> Duration {
+    Duration::from_secs(now().as_secs().saturating_sub(self.created_at))
+  }
+}
+
+/// Reports which failed to be delivered, one file per report named after its sequence number, so
+/// that they are replayed in order, even after a restart.
+pub(crate) struct Outbox {
+  dir:     PathBuf,
+  /// The queued reports, oldest first, including the ones whose file is still being written.
+  entries: Mutex<VecDeque<Queued>>,
+  pushed:  Notify,
+}
+
+/// A queued report.
+#[derive(Clone, Copy)]
+struct Queued {
+  sequence: u128,
+  size:     u64,
+  /// Whether its file is written yet, before which it is not replayed, nor any report after it.
+  written:  bool,
+}
+
+impl Outbox {
+  /// Open the outbox in the directory, picking up the reports queued before a restart.
+  pub(crate) fn open(dir: PathBuf) -> Result<Self> {
+    std::fs::create_dir_all(&dir)?;
+
+    let mut entries = vec![];
+    for file in std::fs::read_dir(&dir)? {
+      let file = file?;
+      let path = file.path();
+      // The outboxes of the targets in fanout mode.
+      if file.file_type()?.is_dir() {
+        continue;
+      }
+      match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => {
+          let sequence = path.file_stem().and_then(|stem| stem.to_str()?.parse::<u128>().ok());
+          match sequence {
+            Some(sequence) => entries.push(Queued {
+              sequence,
+              size: file.metadata()?.len(),
+              written: true,
+            }),
+            None => log::warn!("Ignored unknown file {} in the outbox", path.display()),
+          }
+        }
+        // Left over by a crash while writing.
+        Some("tmp") => std::fs::remove_file(&path)?,
+        _ => log::warn!("Ignored unknown file {} in the outbox", path.display()),
+      }
+    }
+    entries.sort_by_key(|queued| queued.sequence);
+    if !entries.is_empty() {
+      log::info!(
+        "Found {} queued report(s) in the outbox {}",
+        entries.len(),
+        dir.display()
+      );
+    }
+
+    Ok(Self {
+      dir,
+      entries: Mutex::new(entries.into()),
+      pushed: Notify::new(),
+    })
+  }
+
+  pub(crate) fn len(&self) -> usize {
+    self.entries.lock().unwrap().len()
+  }
+
+  /// Whether no report is queued, nor being written.
+  pub(crate) fn is_empty(&self) -> bool {
+    self.entries.lock().unwrap().is_empty()
+  }
+
+  /// Queue the report, dropping the oldest ones beyond the size limit. The report takes its place
+  /// in the queue at once, so that the reports after it are queued behind it, then its file is
+  /// written and synced on a blocking thread, outside the lock.
+  pub(crate) async fn push(&self, entry: &Entry, config: &OutboxConfig) -> Result<()> {
+    let content = serde_json::to_string(entry)?;
+
+    let (sequence, dropped) = {
+      let mut entries = self.entries.lock().unwrap();
+      let last = entries.back().map(|queued| queued.sequence).unwrap_or_default();
+      let sequence = now().as_nanos().max(last + 1);
+      entries.push_back(Queued {
+        sequence,
+        size: content.len() as u64 + 1,
+        written: false,
+      });
+      (sequence, trim(&mut entries, *config.max_bytes()))
+    };
+    self.delete(dropped).await;
+
+    let path = self.path(sequence);
+    let written = tokio::task::spawn_blocking(move || write_private(&path, &content))
+      .await
+      .map_err(|e| Error::new(ErrorKind::Other, e))
+      .and_then(|written| written);
+
+    let queued = {
+      let mut entries = self.entries.lock().unwrap();
+      let queued = entries.iter().position(|queued| queued.sequence == sequence);
+      match (queued, &written) {
+        (Some(at), Ok(())) => entries[at].written = true,
+        (Some(at), Err(_)) => {
+          entries.remove(at);
+        }
+        (None, _) => {}
+      }
+      queued.is_some()
+    };
+    // Dropped beyond the size limit while it was being written.
+    if !queued {
+      self.delete(vec![sequence]).await;
+    }
+    // Whether it is written or not, the reports after it may be replayed now.
+    self.pushed.notify_one();
+    written
+  }
+
+  /// The oldest queued report, dropping the ones older than the age limit or unreadable. There is
+  /// none while the oldest one is still being written. The files are read on a blocking thread.
+  pub(crate) async fn front(&self, config: &OutboxConfig) -> Option<(u128, Entry)> {
+    loop {
+      let front = *self.entries.lock().unwrap().front()?;
+      if !front.written {
+        return None;
+      }
+
+      let path = self.path(front.sequence);
+      let entry = tokio::task::spawn_blocking(move || std::fs::read(path))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|read| read.map_err(|e| e.to_string()))
+        .and_then(|content| serde_json::from_slice::<Entry>(&content).map_err(|e| e.to_string()));
+      match entry {
+        Ok(entry) if entry.age() <= *config.max_age() => return Some((front.sequence, entry)),
+        Ok(entry) => log::warn!(
+          "Dropped the queued {} older than {:?}",
+          entry.kind,
+          config.max_age()
+        ),
+        Err(e) => log::warn!(
+          "Dropped the unreadable queued report {}: {}",
+          front.sequence,
+          e
+        ),
+      }
+      self.remove(front.sequence).await;
+    }
+  }
+
+  /// Remove the report, once delivered or rejected.
+  pub(crate) async fn remove(&self, sequence: u128) {
+    self.entries.lock().unwrap().retain(|queued| queued.sequence != sequence);
+    self.delete(vec![sequence]).await;
+  }
+
+  /// Wait until a report is queued.
+  pub(crate) async fn pushed(&self) {
+    self.pushed.notified().await
+  }
+
+  fn path(&self, sequence: u128) -> PathBuf {
+    self.dir.join(format!("{:039}.json", sequence))
+  }
+
+  /// Delete the files of the reports on a blocking thread.
+  async fn delete(&self, sequences: Vec<u128>) {
+    if sequences.is_empty() {
+      return;
+    }
+    let paths: Vec<_> = sequences.into_iter().map(|sequence| self.path(sequence)).collect();
+    let _ = tokio::task::spawn_blocking(move || {
+      for path in paths {
+        if let Err(e) = std::fs::remove_file(&path) {
+          log::warn!("Failed to remove queued report {}: {}", path.display(), e);
+        }
+      }
+    })
+    .await;
+  }
+}
+
+/// Drop the oldest reports beyond the size limit, keeping the newest one, telling the sequence
+/// numbers of the ones dropped.
+fn trim(entries: &mut VecDeque<Queued>, max_bytes: u64) -> Vec<u128> {
+  let mut dropped = vec![];
+  let mut size: u64 = entries.iter().map(|queued| queued.size).sum();
+  while size > max_bytes && entries.len() > 1 {
+    let oldest = entries.pop_front().unwrap();
+    log::warn!(
+      "The outbox is over {} bytes, dropped the oldest queued report",
+      max_bytes
+    );
+    dropped.push(oldest.sequence);
+    size -= oldest.size;
+  }
+  dropped
+}
+
+fn now() -> Duration {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_outbox() {
+    let dir = std::env::temp_dir().join("cmdb-agent-test-outbox");
+    let _ = std::fs::remove_dir_all(&dir);
+    let config: OutboxConfig = toml::from_str("max_bytes = 450").unwrap();
+
+    let outbox = Outbox::open(dir.clone()).unwrap();
+    for n in 0..3 {
+      let body = serde_json::json!({ "n": n });
+      let entry = Entry::new(ReportKind::Inventory, ReportId::new(n), Some(body));
+      outbox.push(&entry, &config).await.unwrap();
+    }
+    let heartbeat = Entry::new(ReportKind::Heartbeat, ReportId::new(3), None);
+    outbox.push(&heartbeat, &config).await.unwrap();
+
+    // Reopened as after a restart, with the oldest report dropped beyond 450 bytes.
+    let outbox = Outbox::open(dir.clone()).unwrap();
+    assert_eq!(outbox.len(), 3);
+    let (sequence, entry) = outbox.front(&config).await.unwrap();
+    assert_eq!(entry.body, Some(serde_json::json!({ "n": 1 })));
+    outbox.remove(sequence).await;
+    let (sequence, _) = outbox.front(&config).await.unwrap();
+    outbox.remove(sequence).await;
+    let (sequence, entry) = outbox.front(&config).await.unwrap();
+    assert_eq!(entry.kind, ReportKind::Heartbeat);
+    assert_eq!(entry.id, heartbeat.id);
+    outbox.remove(sequence).await;
+    assert!(outbox.is_empty());
+    assert!(outbox.front(&config).await.is_none());
+
+    // A report still being written holds back the ones queued after it.
+    let writing = Queued {
+      sequence: 1,
+      size:     0,
+      written:  false,
+    };
+    outbox.entries.lock().unwrap().push_back(writing);
+    assert!(!outbox.is_empty());
+    outbox.push(&heartbeat, &config).await.unwrap();
+    assert!(outbox.front(&config).await.is_none());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,122 @@
+use std::io::Result;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Record;
+use crate::collect::sink::Sink;
+
+/// Appends the reports to a file as NDJSON. Beyond `max_bytes`, the file is rotated to `<path>.1`,
+/// shifting the older ones up to `<path>.<max_files>`, the oldest being deleted.
+///
+/// The file is opened for every report, so that it can be moved away by an external tool as well.
+/// It is written on a blocking thread, by a clone of the sink sharing its lock.
+#[derive(Clone)]
+pub(crate) struct FileSink {
+  path:      PathBuf,
+  max_bytes: u64,
+  max_files: usize,
+  /// Serializes the writes and rotations of the concurrent reports.
+  lock:      Arc<Mutex<()>>,
+}
+
+impl FileSink {
+  pub(crate) fn new(path: PathBuf, max_bytes: u64, max_files: usize) -> Self {
+    Self {
+      path,
+      max_bytes,
+      max_files,
+      lock: Default::default(),
+    }
+  }
+
+  fn append(&self, line: &str) -> Result<()> {
+    let _lock = self.lock.lock().unwrap();
+
+    let size = std::fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or_default();
+    if self.max_bytes > 0 && size > 0 && size + line.len() as u64 > self.max_bytes {
+      self.rotate()?;
+    }
+
+    if let Some(parent) = self.path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+    file.write_all(line.as_bytes())
+  }
+
+  fn rotate(&self) -> Result<()> {
+    if self.max_files == 0 {
+      return std::fs::remove_file(&self.path);
+    }
+    for n in (1..self.max_files).rev() {
+      let from = rotated(&self.path, n);
+      if from.exists() {
+        std::fs::rename(from, rotated(&self.path, n + 1))?;
+      }
+    }
+    std::fs::rename(&self.path, rotated(&self.path, 1))
+  }
+}
+
+#[async_trait::async_trait]
+impl Sink for FileSink {
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    let sink = self.clone();
+    let written = match Record::line(kind, id, body) {
+      Ok(line) => tokio::task::spawn_blocking(move || sink.append(&line))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e))),
+      Err(e) => Err(e),
+    };
+    Delivery::from(
+      written
+        .map_err(|e| std::io::Error::new(e.kind(), format!("file {}: {}", self.path.display(), e))),
+    )
+  }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+  let mut name = path.as_os_str().to_os_string();
+  name.push(format!(".{}", n));
+  PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_rotate() {
+    let dir = std::env::temp_dir().join("cmdb-agent-test-file-sink");
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("reports.ndjson");
+    let sink = FileSink::new(path.clone(), 10, 2);
+
+    for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+      sink.append(line).unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+    assert_eq!(
+      std::fs::read_to_string(rotated(&path, 1)).unwrap(),
+      "third\n"
+    );
+    assert_eq!(
+      std::fs::read_to_string(rotated(&path, 2)).unwrap(),
+      "second\n"
+    );
+    assert!(!rotated(&path, 3).exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
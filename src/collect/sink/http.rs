@@ -0,0 +1,310 @@
+use std::sync::Arc;
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use reqwest_middleware::ClientWithMiddleware;
+use reqwest_middleware::RequestBuilder;
+
+use crate::collect::http;
+use crate::collect::http::Signer;
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Sink;
+use crate::collect::state;
+use crate::collect::state::ReportState;
+use crate::config::Config;
+use crate::config::TargetConfig;
+use crate::identity::enroll::Enroller;
+use crate::identity::Identity;
+use crate::schema::Enrollment;
+use crate::schema::HeartbeatResponse;
+
+pub(crate) const AGENT_ID_HEADER_KEY: &str = "X-CMDB-Agent-Id";
+const BASE_VERSION_HEADER_KEY: &str = "X-CMDB-Base-Version";
+const INVENTORY_HASH_HEADER_KEY: &str = "X-CMDB-Inventory-Hash";
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// Posts the reports to a CMDB server, with the client carrying the credentials of the target.
+pub(crate) struct HttpSink {
+  target:       TargetConfig,
+  client:       ClientWithMiddleware,
+  /// Send a JSON Patch from the inventory last acknowledged instead of the whole one.
+  delta:        bool,
+  enroller:     Arc<Enroller>,
+  state:        Arc<ReportState>,
+  /// Signs the heartbeats sent over the channel, as `SEND` to its path.
+  signer:       Option<Signer>,
+  channel_path: String,
+}
+
+impl HttpSink {
+  pub(crate) fn new(
+    config: &Config,
+    target: TargetConfig,
+    identity: Option<Arc<Identity>>,
+    enroller: Arc<Enroller>,
+    state: Arc<ReportState>,
+  ) -> std::io::Result<Self> {
+    let credentials = target.credentials().as_ref().or(config.http().credentials().as_ref());
+    let client = http::default_client(
+      config.http(),
+      credentials,
+      config.signing(),
+      identity.clone(),
+    )?;
+    Ok(Self {
+      target,
+      client,
+      delta: *config.inventory().delta(),
+      enroller,
+      state,
+      signer: Signer::new(config.signing(), identity),
+      channel_path: config.channel().path().clone(),
+    })
+  }
+
+  /// Send the inventory as a JSON Patch if the target acknowledged a base for it, or else whole.
+  async fn deliver_inventory(&self, id: Option<&ReportId>, body: serde_json::Value) -> Delivery {
+    let name = self.target.name();
+    let hash = state::content_hash(&body);
+
+    if let Some((base, patch)) =
+      self.delta.then(|| self.state.inventory_delta(name, &body)).flatten()
+    {
+      match self.deliver_delta(id, &base, &hash, patch).await {
+        Delivery::Delivered => {
+          log::debug!("Reported machine info to {} as a delta from {}", name, base);
+          self.state.acknowledge_inventory(name, &hash, body).await;
+          return Delivery::Delivered;
+        }
+        Delivery::Answered(StatusCode::CONFLICT) => {
+          log::info!(
+            "CMDB server {} does not know the base {} of the delta, upload the whole machine info",
+            name,
+            base
+          );
+          self.state.forget_inventory(name).await;
+        }
+        delivery if delivery.is_transient() => return delivery,
+        delivery => {
+          log::warn!(
+            "Failed to report machine info to {} as a delta, upload it whole: {}",
+            name,
+            delivery
+          );
+        }
+      }
+    }
+
+    let path = self.target.inventory_path();
+    let delivery = self.post(path, id, Some(&hash), Some(&body)).await;
+    if self.delta && matches!(delivery, Delivery::Delivered) {
+      self.state.acknowledge_inventory(name, &hash, body).await;
+    }
+    delivery
+  }
+
+  /// Post the report. The hash names the inventory, as the base of the next deltas.
+  async fn post(
+    &self,
+    path: &str,
+    id: Option<&ReportId>,
+    hash: Option<&str>,
+    body: Option<&serde_json::Value>,
+  ) -> Delivery {
+    let urls = self.target.urls(path);
+    let response = self
+      .send(&urls, |url| {
+        let mut request = identify(self.client.post(url), id);
+        if let Some(hash) = hash {
+          request = request.header(INVENTORY_HASH_HEADER_KEY, hash);
+        }
+        match body {
+          Some(body) => request.json(body),
+          None => request,
+        }
+      })
+      .await;
+
+    Delivery::from(response)
+  }
+
+  /// Sign the message to send over the channel: the signature headers go in its `signature` field,
+  /// computed over the compact JSON of the message without it, with sorted keys.
+  fn sign_message(
+    &self,
+    message: &mut serde_json::Map<String, serde_json::Value>,
+  ) -> std::io::Result<()> {
+    let Some(signer) = &self.signer else {
+      return Ok(());
+    };
+    let bytes = serde_json::to_vec(message)?;
+    let headers = signer.headers("SEND", &self.channel_path, &bytes, signer.active())?;
+    message.insert("signature".to_string(), headers.into_iter().collect());
+    Ok(())
+  }
+
+  /// Post the heartbeat, queueing the commands the server answers with. The heartbeat goes over
+  /// the channel instead while it is open to this target.
+  async fn deliver_heartbeat(
+    &self,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    if let Some(body) = &body {
+      // Messages over the channel have no headers, the heartbeat carries its identifier and
+      // signature instead.
+      let mut message = body.clone();
+      if let Some(message) = message.as_object_mut() {
+        if let Ok(serde_json::Value::Object(id)) = serde_json::to_value(id) {
+          message.extend(id);
+        }
+        if let Err(e) = self.sign_message(message) {
+          return Delivery::from(Err(e));
+        }
+      }
+      if self.state.send_over_channel(self.target.name(), &message) {
+        return Delivery::Delivered;
+      }
+    }
+
+    let urls = self.target.urls(self.target.heartbeat_path());
+    let response = self
+      .send(&urls, |url| {
+        let request = identify(self.client.post(url), id);
+        match &body {
+          Some(body) => request.json(body),
+          None => request,
+        }
+      })
+      .await;
+
+    match response {
+      Ok(response) if response.status().is_success() => {
+        // Servers without commands may answer with an empty body.
+        match response.json::<HeartbeatResponse>().await {
+          Ok(answer) => self.state.commands().receive(answer.commands().clone()),
+          Err(e) => log::debug!(
+            "Ignored the answer of {} to the heartbeat: {}",
+            self.target.name(),
+            e
+          ),
+        }
+        Delivery::Delivered
+      }
+      response => Delivery::from(response),
+    }
+  }
+
+  /// Send the inventory as a JSON Patch from the base one, which the server answers with
+  /// `409 Conflict` if it does not know the base.
+  async fn deliver_delta(
+    &self,
+    id: Option<&ReportId>,
+    base: &str,
+    hash: &str,
+    patch: Vec<u8>,
+  ) -> Delivery {
+    let urls = self.target.urls(self.target.inventory_path());
+    let response = self
+      .send(&urls, |url| {
+        identify(self.client.post(url), id)
+          .header(CONTENT_TYPE, JSON_PATCH_CONTENT_TYPE)
+          .header(BASE_VERSION_HEADER_KEY, base)
+          .header(INVENTORY_HASH_HEADER_KEY, hash)
+          .body(patch.clone())
+      })
+      .await;
+
+    Delivery::from(response)
+  }
+
+  /// Send the request to each endpoint in order until one of them answers, holding the next
+  /// deliveries if the server asks to retry later.
+  async fn send<F>(
+    &self,
+    urls: &[String],
+    request: F,
+  ) -> reqwest_middleware::Result<reqwest::Response>
+  where
+    F: Fn(&str) -> RequestBuilder,
+  {
+    let response = self.send_authorized(urls, request).await;
+    let retry_after =
+      |response: &reqwest::Response| http::retry_after(response.status(), response.headers());
+    if let Some(delay) = response.as_ref().ok().and_then(retry_after) {
+      self.state.throttle(self.target.name(), delay);
+    }
+    response
+  }
+
+  /// Send the request to each endpoint in order until one of them answers. Targets without
+  /// credentials of their own are sent the credential of the enrolled agent, if any, who enrolls
+  /// again if the server rejects it.
+  async fn send_authorized<F>(
+    &self,
+    urls: &[String],
+    request: F,
+  ) -> reqwest_middleware::Result<reqwest::Response>
+  where
+    F: Fn(&str) -> RequestBuilder,
+  {
+    if self.target.credentials().is_some() {
+      return http::send(urls, request).await;
+    }
+
+    let enrollment = self.enroller.enrollment().await;
+    let response = http::send(urls, |url| authorize(request(url), enrollment.as_ref())).await;
+
+    match (&response, &enrollment) {
+      (Ok(rejected), Some(enrollment)) if rejected.status() == StatusCode::UNAUTHORIZED => {
+        match self.enroller.reenroll(enrollment).await {
+          Some(enrollment) => {
+            http::send(urls, |url| authorize(request(url), Some(&enrollment))).await
+          }
+          None => response,
+        }
+      }
+      _ => response,
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl Sink for HttpSink {
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    match (kind, body) {
+      (ReportKind::Inventory, Some(body)) => self.deliver_inventory(id, body).await,
+      (ReportKind::Inventory, None) => {
+        self.post(self.target.inventory_path(), id, None, None).await
+      }
+      (ReportKind::Heartbeat, body) => self.deliver_heartbeat(id, body).await,
+    }
+  }
+}
+
+/// Identify the report, so that the server can tell the retries and replays of a report from a
+/// new one.
+fn identify(mut request: RequestBuilder, id: Option<&ReportId>) -> RequestBuilder {
+  for (name, value) in sink::id_headers(id) {
+    request = request.header(name, value);
+  }
+  request
+}
+
+/// Send the credential of the enrolled agent instead of the configured one.
+fn authorize(request: RequestBuilder, enrollment: Option<&Enrollment>) -> RequestBuilder {
+  match enrollment {
+    Some(enrollment) => request
+      .bearer_auth(enrollment.credential())
+      .header(AGENT_ID_HEADER_KEY, enrollment.agent_id()),
+    None => request,
+  }
+}
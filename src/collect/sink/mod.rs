@@ -0,0 +1,244 @@
+use std::fmt::Display;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::collect;
+use crate::collect::http::Signer;
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+
+pub mod file;
+pub mod http;
+pub mod mqtt;
+pub mod nats;
+pub mod stdout;
+pub mod unix;
+
+/// Headers identifying a report, see [`ReportId`].
+pub(crate) const IDEMPOTENCY_KEY_HEADER_KEY: &str = "Idempotency-Key";
+pub(crate) const SEQUENCE_HEADER_KEY: &str = "X-CMDB-Sequence";
+
+/// Where the reports of a target go, the CMDB server or a local consumer.
+#[async_trait::async_trait]
+pub(crate) trait Sink: Send + Sync {
+  /// Deliver the report, already restricted to the sections the target receives, along with its
+  /// identifier if it has one.
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery;
+}
+
+/// How the delivery of a report turned out.
+pub(crate) enum Delivery {
+  Delivered,
+  /// The server answers with an error status.
+  Answered(StatusCode),
+  Unreachable(reqwest_middleware::Error),
+  /// A local sink fails to write the report, e.g. its consumer is not listening.
+  Failed(std::io::Error),
+  /// The report was queued for a target which is no longer configured.
+  NoTarget,
+  /// The circuit breaker of the target is open, for the given time yet.
+  Held(Duration),
+}
+
+impl Delivery {
+  /// Whether the report is worth delivering again later.
+  pub(crate) fn is_transient(&self) -> bool {
+    match self {
+      Delivery::Delivered | Delivery::NoTarget => false,
+      Delivery::Answered(status) => collect::http::is_transient(*status),
+      Delivery::Unreachable(_) | Delivery::Failed(_) | Delivery::Held(_) => true,
+    }
+  }
+}
+
+impl Display for Delivery {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Delivery::Delivered => write!(f, "delivered"),
+      Delivery::Answered(status) => write!(f, "HTTP status: {}", status),
+      Delivery::Unreachable(e) => write!(f, "{}", e),
+      Delivery::Failed(e) => write!(f, "{}", e),
+      Delivery::NoTarget => write!(f, "no such target"),
+      Delivery::Held(remaining) => write!(
+        f,
+        "circuit breaker is open for {:?}",
+        Duration::from_secs(remaining.as_secs().max(1))
+      ),
+    }
+  }
+}
+
+impl From<reqwest_middleware::Result<reqwest::Response>> for Delivery {
+  fn from(response: reqwest_middleware::Result<reqwest::Response>) -> Self {
+    match response {
+      Ok(response) if response.status().is_success() => Delivery::Delivered,
+      Ok(response) => Delivery::Answered(response.status()),
+      Err(e) => Delivery::Unreachable(e),
+    }
+  }
+}
+
+impl From<std::io::Result<()>> for Delivery {
+  fn from(result: std::io::Result<()>) -> Self {
+    match result {
+      Ok(()) => Delivery::Delivered,
+      Err(e) => Delivery::Failed(e),
+    }
+  }
+}
+
+/// A report as written by the local sinks, one JSON object per line, here wrapped:
+///
+/// ```json
+/// {"kind":"inventory","timestamp":"2024-01-01T00:00:00Z","sequence":42,"idempotencyKey":"...",
+///  "body":{"hostname":"node-01"}}
+/// ```
+#[derive(Serialize)]
+struct Record<'a> {
+  kind:      ReportKind,
+  #[serde(with = "humantime_serde")]
+  timestamp: SystemTime,
+  #[serde(flatten)]
+  id:        Option<&'a ReportId>,
+  body:      Option<serde_json::Value>,
+}
+
+impl Record<'_> {
+  /// The report as a line of NDJSON, with its trailing newline.
+  fn line(
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> std::io::Result<String> {
+    let record = Record {
+      kind,
+      timestamp: SystemTime::now(),
+      id,
+      body,
+    };
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    Ok(line)
+  }
+}
+
+/// Topic or subject the reports are published to, where `{hostname}`, `{serial}` and `{kind}` are
+/// replaced.
+pub(crate) struct Topic {
+  template: String,
+  hostname: String,
+  serial:   String,
+}
+
+impl Topic {
+  /// Resolve the placeholders of the machine once, as they do not change while the agent runs.
+  pub(crate) fn new(template: String) -> Self {
+    let resolve = |placeholder: &str, get: fn() -> std::io::Result<String>| {
+      if !template.contains(placeholder) {
+        return String::new();
+      }
+      get().unwrap_or_else(|e| {
+        log::warn!(
+          "Failed to resolve {} in topic {}: {}",
+          placeholder,
+          template,
+          e
+        );
+        "unknown".to_string()
+      })
+    };
+    let hostname = resolve("{hostname}", collect::host::get_hostname);
+    let serial = resolve("{serial}", collect::get_system_serial_number);
+    Self {
+      template,
+      hostname,
+      serial,
+    }
+  }
+
+  pub(crate) fn render(&self, kind: ReportKind) -> String {
+    self
+      .template
+      .replace("{hostname}", &self.hostname)
+      .replace("{serial}", &self.serial)
+      .replace("{kind}", kind.name())
+  }
+}
+
+/// A report as published to a message broker: the JSON body, heartbeat or machine info as posted
+/// to the CMDB server, with the headers identifying and signing it.
+struct Message {
+  topic:   String,
+  payload: Vec<u8>,
+  headers: Vec<(&'static str, String)>,
+}
+
+impl Message {
+  const CONTENT_TYPE: &'static str = "application/json";
+
+  /// Sign the message like an HTTP request, with `PUBLISH` as the method and the topic as the
+  /// path.
+  fn new(
+    topic: &Topic,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+    signer: Option<&Signer>,
+  ) -> std::io::Result<Self> {
+    let topic = topic.render(kind);
+    let payload = match body {
+      Some(body) => serde_json::to_vec(&body)?,
+      None => vec![],
+    };
+    let mut headers = id_headers(id);
+    if let Some(signer) = signer {
+      headers.extend(signer.headers("PUBLISH", &topic, &payload, signer.active())?);
+    }
+    Ok(Self {
+      topic,
+      payload,
+      headers,
+    })
+  }
+}
+
+/// The headers identifying the report, if it has an identifier.
+pub(crate) fn id_headers(id: Option<&ReportId>) -> Vec<(&'static str, String)> {
+  match id {
+    Some(id) => vec![
+      (SEQUENCE_HEADER_KEY, id.sequence.to_string()),
+      (IDEMPOTENCY_KEY_HEADER_KEY, id.idempotency_key.clone()),
+    ],
+    None => vec![],
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_topic() {
+    let topic = Topic {
+      template: "cmdb/{hostname}/{serial}/{kind}".to_string(),
+      hostname: "node-01".to_string(),
+      serial:   "SN0".to_string(),
+    };
+    assert_eq!(
+      topic.render(ReportKind::Heartbeat),
+      "cmdb/node-01/SN0/heartbeat"
+    );
+    assert_eq!(
+      topic.render(ReportKind::Inventory),
+      "cmdb/node-01/SN0/inventory"
+    );
+  }
+}
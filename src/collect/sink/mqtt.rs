@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rumqttc::tokio_rustls::rustls;
+use rumqttc::tokio_rustls::rustls::pki_types::CertificateDer;
+use rumqttc::tokio_rustls::rustls::pki_types::Der;
+use rumqttc::tokio_rustls::rustls::pki_types::PrivateKeyDer;
+use rumqttc::tokio_rustls::rustls::pki_types::PrivatePkcs1KeyDer;
+use rumqttc::tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+use rumqttc::tokio_rustls::rustls::pki_types::PrivateSec1KeyDer;
+use rumqttc::tokio_rustls::rustls::pki_types::TrustAnchor;
+use rumqttc::tokio_rustls::rustls::ClientConfig;
+use rumqttc::tokio_rustls::rustls::RootCertStore;
+use rumqttc::v5::mqttbytes::v5::Packet;
+use rumqttc::v5::mqttbytes::v5::PubAckReason;
+use rumqttc::v5::mqttbytes::v5::PubCompReason;
+use rumqttc::v5::mqttbytes::v5::PubRecReason;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::AsyncClient;
+use rumqttc::v5::Event;
+use rumqttc::v5::EventLoop;
+use rumqttc::v5::MqttOptions;
+use rumqttc::Outgoing;
+use rumqttc::TlsConfiguration;
+use rumqttc::Transport;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+
+use crate::collect::host::get_hostname;
+use crate::collect::http::Signer;
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Message;
+use crate::collect::sink::Sink;
+use crate::collect::sink::Topic;
+use crate::collect::tls;
+use crate::config::Config;
+use crate::config::Credentials;
+use crate::config::HttpConfig;
+use crate::config::TargetConfig;
+use crate::config::TlsConfig;
+use crate::identity::Identity;
+
+/// Topic of the reports if the target has none.
+const DEFAULT_TOPIC: &str = "cmdb/{hostname}/{kind}";
+/// Delay before connecting again once the connection to the broker is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Publications waiting for the event loop to send them.
+const CAPACITY: usize = 16;
+
+/// Publishes the reports to an MQTT broker over MQTT 5, the signature going along as user
+/// properties. The broker retains the last inventory if `retain` is set, for new subscribers to
+/// get it at once.
+///
+/// The connection is made on the first report, then kept up by a background task which
+/// reconnects once it is lost. A report is delivered once the broker acknowledges it as its QoS
+/// asks, within `http.timeout`.
+pub(crate) struct MqttSink {
+  target:     TargetConfig,
+  http:       HttpConfig,
+  topic:      Topic,
+  signer:     Option<Signer>,
+  connection: OnceCell<Connection>,
+}
+
+struct Connection {
+  client:    AsyncClient,
+  /// Whether the broker acknowledged the connection.
+  connected: watch::Receiver<bool>,
+  acks:      Arc<Mutex<Acks>>,
+  /// Held while a publication is handed to the event loop, so that they are sent in the order of
+  /// `Acks::unsent`.
+  sending:   tokio::sync::Mutex<()>,
+  task:      JoinHandle<()>,
+}
+
+/// The publications waiting for the broker to acknowledge them, as the QoS asks: a PUBACK at QoS
+/// 1, a PUBCOMP at QoS 2, and their sending alone at QoS 0.
+#[derive(Default)]
+struct Acks {
+  /// Publications handed to the event loop, in order, which has not sent them yet.
+  unsent:  VecDeque<oneshot::Sender<Result<()>>>,
+  /// Publications sent by packet id. The event loop sends them again under the same packet id
+  /// after a reconnection.
+  unacked: HashMap<u16, oneshot::Sender<Result<()>>>,
+}
+
+impl Acks {
+  fn sent(&mut self, pkid: u16) {
+    if self.unacked.contains_key(&pkid) {
+      return;
+    }
+    let Some(ack) = self.unsent.pop_front() else {
+      return;
+    };
+    if pkid == 0 {
+      let _ = ack.send(Ok(()));
+    } else {
+      self.unacked.insert(pkid, ack);
+    }
+  }
+
+  fn acknowledged(&mut self, pkid: u16, refused: Option<String>) {
+    if let Some(ack) = self.unacked.remove(&pkid) {
+      let _ = ack.send(match refused {
+        None => Ok(()),
+        Some(reason) => Err(Error::new(
+          ErrorKind::Other,
+          format!("the MQTT broker refused the report: {}", reason),
+        )),
+      });
+    }
+  }
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    self.task.abort();
+  }
+}
+
+impl MqttSink {
+  pub(crate) fn new(
+    config: &Config,
+    target: TargetConfig,
+    identity: Option<Arc<Identity>>,
+  ) -> Self {
+    let topic = target.topic().clone().unwrap_or_else(|| DEFAULT_TOPIC.to_string());
+    Self {
+      topic: Topic::new(topic),
+      http: config.http().clone(),
+      signer: Signer::new(config.signing(), identity),
+      target,
+      connection: OnceCell::new(),
+    }
+  }
+
+  fn options(&self) -> Result<MqttOptions> {
+    let endpoint = self.target.endpoints().first().map(String::as_str).unwrap_or_default();
+    let url = reqwest::Url::parse(endpoint).map_err(|e| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        format!("malformed URL `{}`: {}", endpoint, e),
+      )
+    })?;
+    let tls = match url.scheme() {
+      "mqtt" => false,
+      "mqtts" => true,
+      _ => {
+        return Err(Error::new(
+          ErrorKind::InvalidInput,
+          format!("unsupported URL `{}`, expected mqtt(s)://host", endpoint),
+        ))
+      }
+    };
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+    let client_id = match self.target.client_id() {
+      Some(client_id) => client_id.clone(),
+      None => format!("cmdb-agent-{}", get_hostname()?),
+    };
+
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_connection_timeout(self.http.connect_timeout().as_secs().max(1));
+    match self.target.credentials() {
+      Some(Credentials::Basic { username, password }) => {
+        let password = password.as_ref().map(|password| password.expose()).transpose()?;
+        options.set_credentials(username.clone(), password.unwrap_or_default());
+      }
+      Some(Credentials::Bearer { .. }) => {
+        return Err(Error::new(
+          ErrorKind::InvalidInput,
+          "MQTT takes basic credentials only",
+        ))
+      }
+      None => {}
+    }
+    if tls {
+      options.set_transport(transport(self.http.tls().as_ref())?);
+    }
+    Ok(options)
+  }
+
+  async fn connect(&self) -> Result<Connection> {
+    let (client, eventloop) = AsyncClient::new(self.options()?, CAPACITY);
+    let (sender, connected) = watch::channel(false);
+    let acks = Arc::new(Mutex::new(Acks::default()));
+    let task = tokio::spawn(poll(
+      eventloop,
+      sender,
+      acks.clone(),
+      self.target.name().clone(),
+    ));
+    Ok(Connection {
+      client,
+      connected,
+      acks,
+      sending: Default::default(),
+      task,
+    })
+  }
+
+  async fn publish(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Result<()> {
+    let message = Message::new(&self.topic, kind, id, body, self.signer.as_ref())?;
+    let connection = self.connection.get_or_try_init(|| self.connect()).await?;
+
+    let mut connected = connection.connected.clone();
+    let waited = tokio::time::timeout(*self.http.connect_timeout(), connected.wait_for(|up| *up))
+      .await
+      .map(|up| up.is_ok());
+    if waited != Ok(true) {
+      return Err(Error::new(
+        ErrorKind::NotConnected,
+        format!(
+          "MQTT broker {} is not connected",
+          self.target.endpoints().join(", ")
+        ),
+      ));
+    }
+
+    let properties = PublishProperties {
+      content_type: Some(Message::CONTENT_TYPE.to_string()),
+      user_properties: message
+        .headers
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect(),
+      ..Default::default()
+    };
+    let retain = *self.target.retain() && kind == ReportKind::Inventory;
+    let (ack, acked) = oneshot::channel();
+    {
+      let _sending = connection.sending.lock().await;
+      connection.acks.lock().unwrap().unsent.push_back(ack);
+      let published = connection
+        .client
+        .publish_with_properties(
+          message.topic,
+          qos(*self.target.qos()),
+          retain,
+          message.payload,
+          properties,
+        )
+        .await;
+      if let Err(e) = published {
+        connection.acks.lock().unwrap().unsent.pop_back();
+        return Err(Error::new(ErrorKind::Other, e));
+      }
+    }
+
+    match tokio::time::timeout(*self.http.timeout(), acked).await {
+      Ok(Ok(acknowledged)) => acknowledged,
+      Ok(Err(_)) => Err(Error::new(
+        ErrorKind::ConnectionAborted,
+        "the connection to the MQTT broker is closed",
+      )),
+      Err(_) => Err(Error::new(
+        ErrorKind::TimedOut,
+        format!(
+          "the MQTT broker did not acknowledge the report within {:?}",
+          self.http.timeout()
+        ),
+      )),
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl Sink for MqttSink {
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    Delivery::from(self.publish(kind, id, body).await)
+  }
+}
+
+/// Drive the connection to the broker, telling whether it is up and which publications it
+/// acknowledged.
+async fn poll(
+  mut eventloop: EventLoop,
+  connected: watch::Sender<bool>,
+  acks: Arc<Mutex<Acks>>,
+  name: String,
+) {
+  loop {
+    match eventloop.poll().await {
+      Ok(Event::Incoming(Packet::ConnAck(_))) => {
+        log::info!("Connected to the MQTT broker of target {}", name);
+        connected.send_replace(true);
+      }
+      Ok(Event::Outgoing(Outgoing::Publish(pkid))) => acks.lock().unwrap().sent(pkid),
+      Ok(Event::Incoming(Packet::PubAck(ack))) => {
+        let refused = !matches!(
+          ack.reason,
+          PubAckReason::Success | PubAckReason::NoMatchingSubscribers
+        );
+        acks
+          .lock()
+          .unwrap()
+          .acknowledged(ack.pkid, refused.then(|| format!("{:?}", ack.reason)));
+      }
+      Ok(Event::Incoming(Packet::PubRec(rec))) => {
+        // The publication goes on to a PUBCOMP unless the broker refuses it here.
+        if !matches!(
+          rec.reason,
+          PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+        ) {
+          acks.lock().unwrap().acknowledged(rec.pkid, Some(format!("{:?}", rec.reason)));
+        }
+      }
+      Ok(Event::Incoming(Packet::PubComp(comp))) => {
+        let refused = comp.reason != PubCompReason::Success;
+        acks
+          .lock()
+          .unwrap()
+          .acknowledged(comp.pkid, refused.then(|| format!("{:?}", comp.reason)));
+      }
+      Ok(_) => {}
+      Err(e) => {
+        if connected.send_replace(false) {
+          log::warn!("Lost the MQTT broker of target {}: {}", name, e);
+        } else {
+          log::debug!(
+            "Failed to connect to the MQTT broker of target {}: {}",
+            name,
+            e
+          );
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+      }
+    }
+  }
+}
+
+/// TLS with the CA bundles, the built-in roots and the client certificate of `http.tls`. rumqttc
+/// takes a newer rustls than the HTTP client, so the configuration is built on its own, without
+/// the pinned public keys which are rejected for MQTT targets.
+fn transport(tls: Option<&TlsConfig>) -> Result<Transport> {
+  let default = TlsConfig::default();
+  let tls = tls.unwrap_or(&default);
+  let invalid = |path: &Path, e: rustls::Error| {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+  };
+
+  let mut roots = RootCertStore::empty();
+  if *tls.builtin_roots() {
+    roots.extend(
+      webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| TrustAnchor {
+        subject:                 Der::from_slice(anchor.subject),
+        subject_public_key_info: Der::from_slice(anchor.spki),
+        name_constraints:        anchor.name_constraints.map(Der::from_slice),
+      }),
+    );
+  }
+  for path in tls.ca_files() {
+    for certificate in certificates(path)? {
+      roots.add(certificate).map_err(|e| invalid(path, e))?;
+    }
+  }
+
+  let builder = ClientConfig::builder().with_root_certificates(roots);
+  let config = match (tls.client_cert(), tls.client_key()) {
+    (Some(cert), Some(key)) => builder
+      .with_client_auth_cert(certificates(cert)?, private_key(key)?)
+      .map_err(|e| invalid(key, e))?,
+    (None, None) => builder.with_no_client_auth(),
+    _ => {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        "client_cert and client_key must be given together",
+      ))
+    }
+  };
+  Ok(Transport::tls_with_config(TlsConfiguration::Rustls(
+    Arc::new(config),
+  )))
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+  let certificates = tls::load_certificates(path)?;
+  Ok(
+    certificates
+      .into_iter()
+      .map(|certificate| CertificateDer::from(certificate.0))
+      .collect(),
+  )
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+  let mut reader = BufReader::new(std::fs::File::open(path)?);
+  rustls_pemfile::read_all(&mut reader)?
+    .into_iter()
+    .find_map(|item| match item {
+      rustls_pemfile::Item::PKCS8Key(key) => Some(PrivatePkcs8KeyDer::from(key).into()),
+      rustls_pemfile::Item::RSAKey(key) => Some(PrivatePkcs1KeyDer::from(key).into()),
+      rustls_pemfile::Item::ECKey(key) => Some(PrivateSec1KeyDer::from(key).into()),
+      _ => None,
+    })
+    .ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: no PEM private key found", path.display()),
+      )
+    })
+}
+
+fn qos(qos: u8) -> QoS {
+  match qos {
+    0 => QoS::AtMostOnce,
+    1 => QoS::AtLeastOnce,
+    _ => QoS::ExactlyOnce,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_acks() {
+    let mut acks = Acks::default();
+    let (at_most_once, mut sent) = oneshot::channel();
+    let (at_least_once, mut acked) = oneshot::channel();
+    let (refused, mut failed) = oneshot::channel();
+    acks.unsent.extend([at_most_once, at_least_once, refused]);
+
+    acks.sent(0);
+    assert!(sent.try_recv().unwrap().is_ok());
+    acks.sent(1);
+    acks.sent(2);
+    assert!(acked.try_recv().is_err());
+
+    // Sent again after a reconnection.
+    acks.sent(1);
+    assert!(acks.unsent.is_empty());
+    acks.acknowledged(1, None);
+    assert!(acked.try_recv().unwrap().is_ok());
+    acks.acknowledged(2, Some("NotAuthorized".to_string()));
+    assert!(failed.try_recv().unwrap().is_err());
+    assert!(acks.unacked.is_empty());
+  }
+}
@@ -0,0 +1,131 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::sync::Arc;
+
+use async_nats::Client;
+use async_nats::ConnectOptions;
+use async_nats::HeaderMap;
+use async_nats::ServerAddr;
+use tokio::sync::OnceCell;
+
+use crate::collect::http::Signer;
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Message;
+use crate::collect::sink::Sink;
+use crate::collect::sink::Topic;
+use crate::collect::tls;
+use crate::config::Config;
+use crate::config::Credentials;
+use crate::config::HttpConfig;
+use crate::config::TargetConfig;
+use crate::identity::Identity;
+
+/// Subject of the reports if the target has none.
+const DEFAULT_SUBJECT: &str = "cmdb.{hostname}.{kind}";
+
+/// Publishes the reports to NATS, the signature going along as headers. A report is delivered once
+/// the server has received it, as NATS keeps no message without JetStream.
+///
+/// The connection is made on the first report, then kept up by the client, which reconnects to
+/// any of the servers once it is lost.
+pub(crate) struct NatsSink {
+  target: TargetConfig,
+  http:   HttpConfig,
+  topic:  Topic,
+  signer: Option<Signer>,
+  client: OnceCell<Client>,
+}
+
+impl NatsSink {
+  pub(crate) fn new(
+    config: &Config,
+    target: TargetConfig,
+    identity: Option<Arc<Identity>>,
+  ) -> Self {
+    let topic = target.topic().clone().unwrap_or_else(|| DEFAULT_SUBJECT.to_string());
+    Self {
+      topic: Topic::new(topic),
+      http: config.http().clone(),
+      signer: Signer::new(config.signing(), identity),
+      target,
+      client: OnceCell::new(),
+    }
+  }
+
+  async fn connect(&self) -> Result<Client> {
+    let servers = self
+      .target
+      .endpoints()
+      .iter()
+      .map(|endpoint| endpoint.parse::<ServerAddr>())
+      .collect::<Result<Vec<_>>>()?;
+
+    let mut options = ConnectOptions::new()
+      .connection_timeout(*self.http.connect_timeout())
+      .name("cmdb-agent");
+    match self.target.credentials() {
+      Some(Credentials::Basic { username, password }) => {
+        let password = password.as_ref().map(|password| password.expose()).transpose()?;
+        options = options.user_and_password(username.clone(), password.unwrap_or_default());
+      }
+      Some(Credentials::Bearer { token }) => options = options.token(token.expose()?),
+      None => {}
+    }
+    if let Some(config) = self.http.tls() {
+      options = options.tls_client_config(tls::client_config(config)?);
+    }
+
+    let client = options.connect(servers).await.map_err(|e| {
+      Error::new(
+        ErrorKind::NotConnected,
+        format!("NATS servers {}: {}", self.target.endpoints().join(", "), e),
+      )
+    })?;
+    log::info!("Connected to NATS for target {}", self.target.name());
+    Ok(client)
+  }
+
+  async fn publish(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Result<()> {
+    let message = Message::new(&self.topic, kind, id, body, self.signer.as_ref())?;
+    let client = self.client.get_or_try_init(|| self.connect()).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", Message::CONTENT_TYPE);
+    for (name, value) in &message.headers {
+      headers.insert(*name, value.as_str());
+    }
+    client
+      .publish_with_headers(message.topic, headers, message.payload.into())
+      .await
+      .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    // Published messages are buffered while the client reconnects.
+    match tokio::time::timeout(*self.http.timeout(), client.flush()).await {
+      Ok(flushed) => flushed.map_err(|e| Error::new(ErrorKind::Other, e)),
+      Err(_) => Err(Error::new(
+        ErrorKind::TimedOut,
+        "timed out waiting for the NATS server",
+      )),
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl Sink for NatsSink {
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    Delivery::from(self.publish(kind, id, body).await)
+  }
+}
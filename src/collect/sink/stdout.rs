@@ -0,0 +1,33 @@
+use std::io::Write;
+
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Record;
+use crate::collect::sink::Sink;
+
+/// Writes the reports to the standard output as NDJSON, for a local pipeline to consume, on a
+/// blocking thread in case the pipeline does not keep up. The logs go to the standard error.
+pub(crate) struct StdoutSink;
+
+#[async_trait::async_trait]
+impl Sink for StdoutSink {
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    let written = match Record::line(kind, id, body) {
+      Ok(line) => tokio::task::spawn_blocking(move || {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(line.as_bytes())?;
+        stdout.flush()
+      })
+      .await
+      .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e))),
+      Err(e) => Err(e),
+    };
+    Delivery::from(written)
+  }
+}
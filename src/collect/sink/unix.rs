@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+
+use crate::collect::outbox::ReportId;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Record;
+use crate::collect::sink::Sink;
+
+/// Writes each report as a line of NDJSON to a Unix domain socket, connecting for every report so
+/// that the listener can be restarted at any time.
+pub(crate) struct UnixSink {
+  path: PathBuf,
+}
+
+impl UnixSink {
+  pub(crate) fn new(path: PathBuf) -> Self {
+    Self { path }
+  }
+
+  async fn write(&self, line: String) -> std::io::Result<()> {
+    let mut stream = UnixStream::connect(&self.path).await.map_err(|e| {
+      std::io::Error::new(e.kind(), format!("socket {}: {}", self.path.display(), e))
+    })?;
+    stream.write_all(line.as_bytes()).await?;
+    stream.shutdown().await
+  }
+}
+
+#[async_trait::async_trait]
+impl Sink for UnixSink {
+  async fn deliver(
+    &self,
+    kind: ReportKind,
+    id: Option<&ReportId>,
+    body: Option<serde_json::Value>,
+  ) -> Delivery {
+    let written = match Record::line(kind, id, body) {
+      Ok(line) => self.write(line).await,
+      Err(e) => Err(e),
+    };
+    Delivery::from(written)
+  }
+}
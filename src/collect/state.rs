@@ -0,0 +1,872 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::collect::outbox::ReportId;
+use crate::collect::sink::Delivery;
+use crate::command::Inbox;
+use crate::config::CircuitBreakerConfig;
+use crate::config::Overlap;
+use crate::schema::AgentState;
+use crate::schema::LastRun;
+use crate::schema::MachineInfo;
+use crate::support::fs::write_private;
+
+/// Name of the file holding the saved state, in the state directory.
+const STATE_FILE: &str = "state.json";
+
+/// Name of the directory holding the inventory last acknowledged by each target, as
+/// `<target>.json`, in the state directory.
+const INVENTORY_DIR: &str = "inventory";
+
+/// Time after which a delivery trying a half-open target is no longer waited for, e.g. when its run
+/// was given up on, and another one tries.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sequence numbers reserved at a time in the saved state.
+const SEQUENCE_BLOCK: u64 = 100;
+
+/// What the agent remembers of its reports, shared by the successive reporters.
+pub(crate) struct ReportState {
+  started:    Instant,
+  /// Where the saved state is kept, if it is.
+  file:       Option<PathBuf>,
+  saved:      Arc<Mutex<SavedState>>,
+  /// Held while the saved state is written, so that the writes do not overtake each other.
+  writing:    Arc<Mutex<()>>,
+  agent:      Mutex<AgentState>,
+  inventory:  Arc<Mutex<InventoryState>>,
+  collection: Mutex<Option<LastRun>>,
+  report:     Mutex<Option<LastRun>>,
+  targets:    Mutex<BTreeMap<String, TargetStatus>>,
+  jobs:       Mutex<BTreeMap<String, JobStatus>>,
+  /// Whether the machine info is being collected, possibly by a run given up on.
+  collecting: AtomicBool,
+  commands:   Inbox,
+  /// Target the channel is open to, and the sender of its messages.
+  channel:    Mutex<Option<(String, UnboundedSender<String>)>>,
+}
+
+impl Default for ReportState {
+  fn default() -> Self {
+    Self {
+      started:    Instant::now(),
+      file:       None,
+      saved:      Default::default(),
+      writing:    Default::default(),
+      agent:      Default::default(),
+      inventory:  Default::default(),
+      collection: Default::default(),
+      report:     Default::default(),
+      targets:    Default::default(),
+      jobs:       Default::default(),
+      collecting: Default::default(),
+      commands:   Default::default(),
+      channel:    Default::default(),
+    }
+  }
+}
+
+/// What the agent keeps of its reports across restarts, so that it resumes where it left off
+/// after a crash.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedState {
+  /// Sequence number up to which the reports may have been numbered. The numbers are reserved in
+  /// blocks, so that the file is not written for every report, and a restart resumes past the
+  /// block.
+  sequence:          u64,
+  /// When a report was last delivered to any target, saved along with the rest of the state rather
+  /// than on every delivery.
+  #[serde(default, with = "humantime_serde")]
+  last_success:      Option<SystemTime>,
+  /// Hash of the inventory last acknowledged by a target, and when.
+  inventory_hash:    Option<String>,
+  #[serde(default, with = "humantime_serde")]
+  inventory_sent_at: Option<SystemTime>,
+  /// Sequence number of the last report.
+  #[serde(skip)]
+  issued:            u64,
+}
+
+#[derive(Default)]
+struct InventoryState {
+  /// Hash and content of the inventory last collected.
+  collected:    Option<(String, serde_json::Value)>,
+  /// Hash and content of the inventory last acknowledged by each target, the base of deltas. They
+  /// are saved as well, so that the deltas go on after a restart.
+  acknowledged: HashMap<String, (String, serde_json::Value)>,
+}
+
+/// How the deliveries to a target turned out.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TargetStatus {
+  #[serde(with = "humantime_serde")]
+  last_success: Option<SystemTime>,
+  #[serde(with = "humantime_serde")]
+  last_failure: Option<SystemTime>,
+  last_error:   Option<String>,
+  /// Failures since the last success.
+  failures:     u32,
+  circuit:      Circuit,
+  /// When the open circuit breaker lets a delivery through again.
+  #[serde(with = "humantime_serde")]
+  open_until:   Option<SystemTime>,
+  /// Transient failures since the target last answered, which open the circuit breaker.
+  #[serde(skip)]
+  unavailable:  u32,
+  /// When the delivery trying the half-open target started, the others being held meanwhile.
+  #[serde(skip)]
+  probing:      Option<SystemTime>,
+}
+
+/// State of the circuit breaker of a target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Circuit {
+  /// Deliveries go ahead.
+  #[default]
+  Closed,
+  /// Deliveries are held until `open_until`.
+  Open,
+  /// A single delivery tries the target again, the others being held until it finishes. Its
+  /// failure opens the circuit again.
+  HalfOpen,
+}
+
+/// How the runs of a scheduled job turned out since the agent started.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct JobStatus {
+  running:       bool,
+  /// Whether a run waits for the one going to finish.
+  queued:        bool,
+  /// Runs which finished, timed out or not.
+  runs:          u64,
+  /// Runs skipped since the previous one was still going.
+  skipped:       u64,
+  timed_out:     u64,
+  #[serde(with = "humantime_serde")]
+  last_started:  Option<SystemTime>,
+  #[serde(with = "humantime_serde")]
+  last_duration: Option<Duration>,
+  /// Whether the machine info is still being collected, which goes on in its thread after the run
+  /// of the inventory job times out, until the collectors are done.
+  collecting:    bool,
+  /// Held by the run going.
+  #[serde(skip)]
+  lock:          Arc<tokio::sync::Mutex<()>>,
+}
+
+impl TargetStatus {
+  /// Hold the deliveries for the given time, unless they already are for longer.
+  fn open(&mut self, duration: Duration) -> bool {
+    let until = SystemTime::now() + duration;
+    if self.circuit == Circuit::Open && self.open_until.is_some_and(|open| open >= until) {
+      return false;
+    }
+    self.circuit = Circuit::Open;
+    self.open_until = Some(until);
+    true
+  }
+
+  fn close(&mut self, target: &str) {
+    if self.circuit != Circuit::Closed {
+      log::info!("Closed the circuit breaker of target {}", target);
+    }
+    self.circuit = Circuit::Closed;
+    self.open_until = None;
+    self.unavailable = 0;
+  }
+}
+
+impl ReportState {
+  /// Pick up the state saved in the state directory, if any, and save it there from then on.
+  pub(crate) fn load(state_dir: &Path) -> std::io::Result<Self> {
+    let path = state_dir.join(STATE_FILE);
+    let saved = match std::fs::read(&path) {
+      Ok(content) => serde_json::from_slice::<SavedState>(&content)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+      Err(e) if e.kind() == ErrorKind::NotFound => SavedState::default(),
+      Err(e) => return Err(e),
+    };
+    log::debug!(
+      "Resume past report {} saved in {}, last delivered {}",
+      saved.sequence,
+      path.display(),
+      saved.last_success.map_or("never".to_string(), |at| {
+        humantime_serde::re::humantime::format_rfc3339_seconds(at).to_string()
+      })
+    );
+    let acknowledged = load_inventories(&state_dir.join(INVENTORY_DIR))?;
+    Ok(Self {
+      file: Some(path),
+      saved: Arc::new(Mutex::new(SavedState {
+        issued: saved.sequence,
+        ..saved
+      })),
+      inventory: Arc::new(Mutex::new(InventoryState {
+        collected: None,
+        acknowledged,
+      })),
+      ..Default::default()
+    })
+  }
+
+  /// Identify a new report, with the next sequence number. The state is saved when a new block of
+  /// numbers is reserved.
+  pub(crate) async fn next_report_id(&self) -> ReportId {
+    let (id, reserved) = {
+      let mut saved = self.saved.lock().unwrap();
+      saved.issued += 1;
+      let reserved = saved.issued > saved.sequence;
+      if reserved {
+        saved.sequence = saved.issued + SEQUENCE_BLOCK - 1;
+      }
+      (ReportId::new(saved.issued), reserved)
+    };
+    if reserved {
+      self.save().await;
+    }
+    id
+  }
+
+  /// Write the saved state on a blocking thread, outside the lock. The file is replaced at once so
+  /// that a crash leaves the previous one behind at worst, and each write takes the state as it is
+  /// by then, so that the last one written is the latest.
+  pub(crate) async fn save(&self) {
+    let Some(path) = &self.file else {
+      return;
+    };
+    let (file, saved, writing) = (path.clone(), self.saved.clone(), self.writing.clone());
+    let written = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+      let _writing = writing.lock().unwrap();
+      let content = serde_json::to_string(&*saved.lock().unwrap())?;
+      write_private(&file, &content)
+    })
+    .await
+    .map_err(|e| Error::new(ErrorKind::Other, e))
+    .and_then(|written| written);
+    if let Err(e) = written {
+      log::warn!("Failed to save the state in {}: {}", path.display(), e);
+    }
+  }
+
+  /// Time since the agent started.
+  pub(crate) fn uptime(&self) -> Duration {
+    self.started.elapsed()
+  }
+
+  pub(crate) fn agent_state(&self) -> AgentState {
+    *self.agent.lock().unwrap()
+  }
+
+  pub(crate) fn set_agent_state(&self, state: AgentState) {
+    *self.agent.lock().unwrap() = state;
+  }
+
+  /// Commands of the server waiting to be run, and the outcome of the ones run.
+  pub(crate) fn commands(&self) -> &Inbox {
+    &self.commands
+  }
+
+  /// The channel is open to the target, and carries its heartbeats until it is closed.
+  pub(crate) fn open_channel(&self, target: &str, sender: UnboundedSender<String>) {
+    *self.channel.lock().unwrap() = Some((target.to_string(), sender));
+  }
+
+  pub(crate) fn close_channel(&self) {
+    *self.channel.lock().unwrap() = None;
+  }
+
+  /// Send the heartbeat over the channel if it is open to the target, telling whether it was.
+  pub(crate) fn send_over_channel(&self, target: &str, heartbeat: &serde_json::Value) -> bool {
+    let channel = self.channel.lock().unwrap();
+    match channel.as_ref() {
+      Some((open, sender)) if open == target => sender.send(heartbeat.to_string()).is_ok(),
+      _ => false,
+    }
+  }
+
+  pub(crate) fn last_collection(&self) -> Option<LastRun> {
+    self.collection.lock().unwrap().clone()
+  }
+
+  pub(crate) fn record_collection(&self, run: LastRun) {
+    *self.collection.lock().unwrap() = Some(run);
+  }
+
+  /// The last report of the inventory, left alone when an unchanged inventory is not reported.
+  pub(crate) fn last_report(&self) -> Option<LastRun> {
+    self.report.lock().unwrap().clone()
+  }
+
+  pub(crate) fn record_report(&self, run: LastRun) {
+    *self.report.lock().unwrap() = Some(run);
+  }
+
+  /// Record the inventory just collected, telling whether it is to be reported: when it differs
+  /// from the last one reported, or when that one is older than the max age.
+  pub(crate) fn collect_inventory(
+    &self,
+    hash: &str,
+    inventory: &serde_json::Value,
+    max_age: Duration,
+  ) -> bool {
+    self.inventory.lock().unwrap().collected = Some((hash.to_string(), inventory.clone()));
+    let saved = self.saved.lock().unwrap();
+    match (&saved.inventory_hash, saved.inventory_sent_at) {
+      (Some(reported), Some(at)) => {
+        reported != hash || at.elapsed().map_or(true, |elapsed| elapsed >= max_age)
+      }
+      _ => true,
+    }
+  }
+
+  /// Record the inventory acknowledged by a target, which is not reported again unless it changes
+  /// or gets too old.
+  pub(crate) async fn report_inventory(&self, hash: &str) {
+    {
+      let mut saved = self.saved.lock().unwrap();
+      saved.inventory_hash = Some(hash.to_string());
+      saved.inventory_sent_at = Some(SystemTime::now());
+    }
+    self.save().await;
+  }
+
+  pub(crate) async fn acknowledge_inventory(
+    &self,
+    target: &str,
+    hash: &str,
+    inventory: serde_json::Value,
+  ) {
+    let acknowledged = (hash.to_string(), inventory);
+    self
+      .inventory
+      .lock()
+      .unwrap()
+      .acknowledged
+      .insert(target.to_string(), acknowledged);
+    self.save_inventory(target).await;
+  }
+
+  /// Forget the inventory last acknowledged by the target, once it no longer knows it.
+  pub(crate) async fn forget_inventory(&self, target: &str) {
+    self.inventory.lock().unwrap().acknowledged.remove(target);
+    self.save_inventory(target).await;
+  }
+
+  /// Write the inventory acknowledged by the target on a blocking thread, outside the lock, or
+  /// delete it once forgotten. As with the saved state, each write takes the inventory as it is by
+  /// then.
+  async fn save_inventory(&self, target: &str) {
+    let Some(dir) = self.file.as_ref().and_then(|file| file.parent()) else {
+      return;
+    };
+    let path = dir.join(INVENTORY_DIR).join(format!("{}.json", target));
+    let (file, name) = (path.clone(), target.to_string());
+    let (inventory, writing) = (self.inventory.clone(), self.writing.clone());
+    let written = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+      let _writing = writing.lock().unwrap();
+      let state = inventory.lock().unwrap();
+      match state.acknowledged.get(&name) {
+        Some(acknowledged) => {
+          let content = serde_json::to_string(acknowledged)?;
+          drop(state);
+          if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+          }
+          write_private(&file, &content)
+        }
+        None => match std::fs::remove_file(&file) {
+          Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+          removed => removed,
+        },
+      }
+    })
+    .await
+    .map_err(|e| Error::new(ErrorKind::Other, e))
+    .and_then(|written| written);
+    if let Err(e) = written {
+      log::warn!(
+        "Failed to save the inventory acknowledged by {} in {}: {}",
+        target,
+        path.display(),
+        e
+      );
+    }
+  }
+
+  /// The serialized RFC 6902 JSON Patch from the inventory last acknowledged by the target to the
+  /// given one, along with the hash of the former. There is none without an acknowledged
+  /// inventory, or if the patch is no smaller than the whole inventory.
+  pub(crate) fn inventory_delta(
+    &self,
+    target: &str,
+    inventory: &serde_json::Value,
+  ) -> Option<(String, Vec<u8>)> {
+    let state = self.inventory.lock().unwrap();
+    let (base, acknowledged) = state.acknowledged.get(target)?;
+
+    let patch = serde_json::to_vec(&json_patch::diff(acknowledged, inventory)).ok()?;
+    let whole = serde_json::to_vec(inventory).ok()?;
+    (patch.len() < whole.len()).then(|| (base.clone(), patch))
+  }
+
+  /// Hash of the inventory last collected, restricted to the given sections if any, for the
+  /// server to tell whether it is in sync.
+  pub(crate) fn inventory_hash(&self, sections: &[String]) -> Option<String> {
+    let state = self.inventory.lock().unwrap();
+    let (hash, inventory) = state.collected.as_ref()?;
+    if sections.is_empty() {
+      Some(hash.clone())
+    } else {
+      Some(content_hash(&select_sections(inventory, sections)))
+    }
+  }
+
+  /// Record how the delivery to the target turned out. Transient failures open its circuit
+  /// breaker once there are enough of them in a row, or at once while it is half-open.
+  pub(crate) fn record_delivery(
+    &self,
+    target: &str,
+    delivery: &Delivery,
+    breaker: &CircuitBreakerConfig,
+  ) {
+    let mut targets = self.targets.lock().unwrap();
+    let status = targets.entry(target.to_string()).or_default();
+    match delivery {
+      // Held by the circuit breaker, the delivery did not reach the target.
+      Delivery::Held(_) => {}
+      Delivery::Delivered => {
+        let now = SystemTime::now();
+        status.probing = None;
+        status.last_success = Some(now);
+        status.failures = 0;
+        status.close(target);
+        self.saved.lock().unwrap().last_success = Some(now);
+      }
+      delivery => {
+        status.probing = None;
+        status.last_failure = Some(SystemTime::now());
+        status.last_error = Some(delivery.to_string());
+        status.failures += 1;
+        if !delivery.is_transient() {
+          // The target answers, even if it does not take the report.
+          status.close(target);
+        } else {
+          status.unavailable += 1;
+          let trip =
+            status.circuit == Circuit::HalfOpen || status.unavailable >= *breaker.failures();
+          if *breaker.enabled() && trip && status.open(*breaker.cooldown()) {
+            log::warn!(
+              "Opened the circuit breaker of target {} after {} failure(s), hold its deliveries \
+               for {:?}",
+              target,
+              status.unavailable,
+              breaker.cooldown()
+            );
+          }
+        }
+      }
+    }
+  }
+
+  /// Hold the deliveries to the target for as long as its server asks.
+  pub(crate) fn throttle(&self, target: &str, delay: Duration) {
+    let mut targets = self.targets.lock().unwrap();
+    let status = targets.entry(target.to_string()).or_default();
+    if status.open(delay) {
+      log::warn!(
+        "Target {} asks to retry after {:?}, hold its deliveries until then",
+        target,
+        delay
+      );
+    }
+  }
+
+  /// How long the circuit breaker of the target holds its deliveries yet, if it does. Once it has
+  /// been open long enough, it turns half-open and lets a single delivery try the target again,
+  /// holding the others until that one finishes.
+  pub(crate) fn hold(&self, target: &str) -> Option<Duration> {
+    let mut targets = self.targets.lock().unwrap();
+    let status = targets.get_mut(target)?;
+    let now = SystemTime::now();
+    match status.circuit {
+      Circuit::Closed => return None,
+      Circuit::Open => {
+        let remaining = status
+          .open_until
+          .and_then(|until| until.duration_since(now).ok())
+          .filter(|remaining| !remaining.is_zero());
+        if remaining.is_some() {
+          return remaining;
+        }
+        log::info!(
+          "Circuit breaker of target {} is half-open, try delivering to it again",
+          target
+        );
+        status.circuit = Circuit::HalfOpen;
+        status.open_until = None;
+      }
+      Circuit::HalfOpen => {
+        let remaining = status
+          .probing
+          .map(|since| PROBE_TIMEOUT.saturating_sub(now.duration_since(since).unwrap_or_default()))
+          .filter(|remaining| !remaining.is_zero());
+        if remaining.is_some() {
+          return remaining;
+        }
+      }
+    }
+    status.probing = Some(now);
+    None
+  }
+
+  /// Let a run of the job start, unless the previous one is still going: then the run is skipped,
+  /// or waits for the previous one to finish if the job queues its runs. The run going holds the
+  /// guard until it finishes.
+  pub(crate) async fn start_job(
+    &self,
+    job: &str,
+    overlap: Overlap,
+  ) -> Option<tokio::sync::OwnedMutexGuard<()>> {
+    let lock = {
+      let mut jobs = self.jobs.lock().unwrap();
+      let status = jobs.entry(job.to_string()).or_default();
+      let lock = status.lock.clone();
+      match lock.try_lock_owned() {
+        Ok(guard) => {
+          status.running = true;
+          status.last_started = Some(SystemTime::now());
+          return Some(guard);
+        }
+        Err(_) if overlap == Overlap::Queue && !status.queued => status.queued = true,
+        Err(_) => {
+          status.skipped += 1;
+          return None;
+        }
+      }
+      status.lock.clone()
+    };
+
+    log::debug!("The {} job waits for its previous run to finish", job);
+    let guard = lock.lock_owned().await;
+    let mut jobs = self.jobs.lock().unwrap();
+    let status = jobs.entry(job.to_string()).or_default();
+    status.queued = false;
+    status.running = true;
+    status.last_started = Some(SystemTime::now());
+    Some(guard)
+  }
+
+  /// Record the end of the run of the job, before its guard is dropped.
+  pub(crate) fn finish_job(&self, job: &str, duration: Duration, timed_out: bool) {
+    let mut jobs = self.jobs.lock().unwrap();
+    let status = jobs.entry(job.to_string()).or_default();
+    status.running = false;
+    status.runs += 1;
+    status.last_duration = Some(Duration::from_millis(duration.as_millis() as u64));
+    if timed_out {
+      status.timed_out += 1;
+    }
+  }
+
+  /// Status of the runs of every job run so far, by name.
+  pub(crate) fn jobs(&self) -> BTreeMap<String, JobStatus> {
+    let mut jobs = self.jobs.lock().unwrap().clone();
+    if let Some(inventory) = jobs.get_mut("inventory") {
+      inventory.collecting = self.collecting.load(Ordering::Acquire);
+    }
+    jobs
+  }
+
+  /// Start collecting the machine info, unless a collection is still going.
+  pub(crate) fn start_collection(&self) -> bool {
+    self
+      .collecting
+      .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+      .is_ok()
+  }
+
+  pub(crate) fn finish_collection(&self) {
+    self.collecting.store(false, Ordering::Release);
+  }
+
+  /// Delivery status of every target reported to so far, by name.
+  pub(crate) fn targets(&self) -> BTreeMap<String, TargetStatus> {
+    self.targets.lock().unwrap().clone()
+  }
+}
+
+/// Keep the given top-level sections of the inventory, along with the ones identifying the
+/// machine. Every section is kept if none is given.
+pub(crate) fn select_sections(
+  inventory: &serde_json::Value,
+  sections: &[String],
+) -> serde_json::Value {
+  match inventory {
+    serde_json::Value::Object(all) if !sections.is_empty() => serde_json::Value::Object(
+      all
+        .iter()
+        .filter(|(section, _)| {
+          MachineInfo::IDENTITY_SECTIONS.contains(&section.as_str()) || sections.contains(section)
+        })
+        .map(|(section, value)| (section.clone(), value.clone()))
+        .collect(),
+    ),
+    inventory => inventory.clone(),
+  }
+}
+
+/// Pick up the inventories acknowledged by the targets, saved in the directory as `[hash,
+/// inventory]`. Unreadable ones are left out, the target receiving a whole inventory again.
+fn load_inventories(dir: &Path) -> std::io::Result<HashMap<String, (String, serde_json::Value)>> {
+  let mut acknowledged = HashMap::new();
+  let files = match std::fs::read_dir(dir) {
+    Ok(files) => files,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(acknowledged),
+    Err(e) => return Err(e),
+  };
+  for file in files {
+    let path = file?.path();
+    let target = match path.extension().and_then(|extension| extension.to_str()) {
+      Some("json") => path.file_stem().and_then(|stem| stem.to_str()),
+      _ => None,
+    };
+    let Some(target) = target else {
+      continue;
+    };
+    let inventory = std::fs::read(&path)
+      .map_err(|e| e.to_string())
+      .and_then(|content| serde_json::from_slice(&content).map_err(|e| e.to_string()));
+    match inventory {
+      Ok(inventory) => {
+        acknowledged.insert(target.to_string(), inventory);
+      }
+      Err(e) => log::warn!(
+        "Ignored the inventory acknowledged in {}: {}",
+        path.display(),
+        e
+      ),
+    }
+  }
+  Ok(acknowledged)
+}
+
+/// SHA-256 of the canonical JSON of the value, hex encoded. Object keys are sorted and there is no
+/// whitespace, so that equal values have the same hash whatever their original layout.
+pub(crate) fn content_hash(value: &serde_json::Value) -> String {
+  let canonical = serde_json::to_vec(value).expect("JSON value is serializable");
+  hex::encode(sha2::Sha256::digest(canonical))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_content_hash() {
+    let value: serde_json::Value =
+      serde_json::from_str(r#"{ "serialNumber": "0", "os": { "os": "linux", "arch": "x86_64" } }"#)
+        .unwrap();
+    let reordered: serde_json::Value =
+      serde_json::from_str(r#"{"os":{"arch":"x86_64","os":"linux"},"serialNumber":"0"}"#).unwrap();
+    assert_eq!(content_hash(&value), content_hash(&reordered));
+    assert_ne!(
+      content_hash(&value),
+      content_hash(&serde_json::json!({ "serialNumber": "1" }))
+    );
+  }
+
+  #[tokio::test]
+  async fn test_collect_inventory() {
+    let state = ReportState::default();
+    let inventory = serde_json::json!({ "hostname": "node-01", "os": { "os": "linux" } });
+    assert!(state.collect_inventory("a", &inventory, Duration::from_secs(60)));
+    state.report_inventory("a").await;
+    assert!(!state.collect_inventory("a", &inventory, Duration::from_secs(60)));
+    assert!(state.collect_inventory("a", &inventory, Duration::ZERO));
+    assert!(state.collect_inventory("b", &inventory, Duration::from_secs(60)));
+    assert_eq!(state.inventory_hash(&[]).as_deref(), Some("b"));
+    assert_eq!(
+      state.inventory_hash(&["os".to_string()]),
+      Some(content_hash(&inventory))
+    );
+  }
+
+  #[test]
+  fn test_send_over_channel() {
+    let state = ReportState::default();
+    let heartbeat = serde_json::json!({ "hostname": "node-01" });
+    assert!(!state.send_over_channel("default", &heartbeat));
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    state.open_channel("default", sender);
+    assert!(!state.send_over_channel("dr", &heartbeat));
+    assert!(state.send_over_channel("default", &heartbeat));
+    assert_eq!(receiver.try_recv().unwrap(), heartbeat.to_string());
+
+    state.close_channel();
+    assert!(!state.send_over_channel("default", &heartbeat));
+  }
+
+  #[test]
+  fn test_circuit_breaker() {
+    let breaker: CircuitBreakerConfig = toml::from_str("failures = 2\ncooldown = \"1h\"").unwrap();
+    let unreachable = Delivery::Failed(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+    let state = ReportState::default();
+    state.record_delivery("primary", &unreachable, &breaker);
+    assert!(state.hold("primary").is_none());
+    state.record_delivery("primary", &unreachable, &breaker);
+    assert!(state.hold("primary").is_some());
+    assert_eq!(state.targets()["primary"].circuit, Circuit::Open);
+
+    // Half-open once the cooldown has passed, and open again on the next failure.
+    state.targets.lock().unwrap().get_mut("primary").unwrap().open_until = Some(SystemTime::now());
+    assert!(state.hold("primary").is_none());
+    assert_eq!(state.targets()["primary"].circuit, Circuit::HalfOpen);
+    // A single delivery tries the target, the others wait for it.
+    assert!(state.hold("primary").is_some());
+    state.record_delivery("primary", &unreachable, &breaker);
+    assert!(state.hold("primary").is_some());
+
+    state.targets.lock().unwrap().get_mut("primary").unwrap().open_until = Some(SystemTime::now());
+    assert!(state.hold("primary").is_none());
+    state.record_delivery("primary", &Delivery::Delivered, &breaker);
+    assert_eq!(state.targets()["primary"].circuit, Circuit::Closed);
+
+    state.throttle("primary", Duration::from_secs(60));
+    assert!(state.hold("primary").unwrap() > Duration::from_secs(50));
+  }
+
+  #[test]
+  fn test_select_sections() {
+    let inventory = serde_json::json!({
+      "hostname": "node-01",
+      "serialNumber": "0",
+      "os": { "os": "linux" },
+      "devices": { "processors": [] },
+    });
+    assert_eq!(select_sections(&inventory, &[]), inventory);
+    assert_eq!(
+      select_sections(&inventory, &["os".to_string()]),
+      serde_json::json!({ "hostname": "node-01", "serialNumber": "0", "os": { "os": "linux" } })
+    );
+  }
+
+  #[tokio::test]
+  async fn test_inventory_delta() {
+    let state = ReportState::default();
+    let mut inventory = serde_json::json!({
+      "hostname": "node-01",
+      "networks": [{ "name": "eth0", "macAddress": "52:54:00:12:34:56" }],
+    });
+    assert!(state.inventory_delta("primary", &inventory).is_none());
+
+    state.acknowledge_inventory("primary", "base", inventory.clone()).await;
+    inventory["hostname"] = "node-02".into();
+    assert!(state.inventory_delta("dr", &inventory).is_none());
+    let (base, patch) = state.inventory_delta("primary", &inventory).unwrap();
+    assert_eq!(base, "base");
+    assert_eq!(
+      serde_json::from_slice::<serde_json::Value>(&patch).unwrap(),
+      serde_json::json!([{ "op": "replace", "path": "/hostname", "value": "node-02" }])
+    );
+
+    state.forget_inventory("primary").await;
+    assert!(state.inventory_delta("primary", &inventory).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_saved_state() {
+    let dir = std::env::temp_dir().join("cmdb-agent-test-state");
+    let _ = std::fs::remove_dir_all(&dir);
+    let inventory = serde_json::json!({ "hostname": "node-01" });
+    let breaker = CircuitBreakerConfig::default();
+
+    let state = ReportState::load(&dir).unwrap();
+    assert_eq!(state.next_report_id().await.sequence, 1);
+    assert_eq!(state.next_report_id().await.sequence, 2);
+    assert!(state.collect_inventory("a", &inventory, Duration::from_secs(60)));
+    state.report_inventory("a").await;
+    // Deliveries are saved along with the rest of the state only.
+    state.record_delivery("primary", &Delivery::Delivered, &breaker);
+    assert!(ReportState::load(&dir).unwrap().saved.lock().unwrap().last_success.is_none());
+    state.save().await;
+
+    // Loaded again as after a restart, past the numbers reserved.
+    let state = ReportState::load(&dir).unwrap();
+    assert!(state.saved.lock().unwrap().last_success.is_some());
+    let id = state.next_report_id().await;
+    assert_eq!(id.sequence, SEQUENCE_BLOCK + 1);
+    assert_ne!(
+      id.idempotency_key,
+      state.next_report_id().await.idempotency_key
+    );
+    assert!(!state.collect_inventory("a", &inventory, Duration::from_secs(60)));
+
+    // Deltas go on from the inventory acknowledged before a restart, until it is forgotten.
+    let base = serde_json::json!({
+      "hostname": "node-01",
+      "networks": [{ "name": "eth0", "macAddress": "52:54:00:12:34:56" }],
+    });
+    state.acknowledge_inventory("primary", "a", base.clone()).await;
+    let mut changed = base;
+    changed["hostname"] = "node-02".into();
+    let state = ReportState::load(&dir).unwrap();
+    assert_eq!(state.inventory_delta("primary", &changed).unwrap().0, "a");
+    state.forget_inventory("primary").await;
+    let state = ReportState::load(&dir).unwrap();
+    assert!(state.inventory_delta("primary", &changed).is_none());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_start_job() {
+    let state = ReportState::default();
+    let running = state.start_job("inventory", Overlap::Skip).await.unwrap();
+    assert!(state.start_job("inventory", Overlap::Skip).await.is_none());
+    assert!(state.start_job("heartbeat", Overlap::Skip).await.is_some());
+
+    // A single run waits for the one going, the next ones are skipped.
+    let state = Arc::new(state);
+    let queued = tokio::spawn({
+      let state = state.clone();
+      async move { state.start_job("inventory", Overlap::Queue).await.is_some() }
+    });
+    tokio::task::yield_now().await;
+    assert!(state.jobs()["inventory"].queued);
+    assert!(state.start_job("inventory", Overlap::Queue).await.is_none());
+
+    state.finish_job("inventory", Duration::from_secs(1), true);
+    drop(running);
+    assert!(queued.await.unwrap());
+    let status = &state.jobs()["inventory"];
+    assert_eq!((status.runs, status.skipped, status.timed_out), (1, 2, 1));
+    assert!(status.running && !status.queued);
+
+    // The collection of a run given up on goes on.
+    assert!(state.start_collection());
+    assert!(state.jobs()["inventory"].collecting);
+    state.finish_collection();
+    assert!(!state.jobs()["inventory"].collecting);
+  }
+}
@@ -1,61 +1,355 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
 use crate::collect;
-use crate::collect::http;
-
-pub(crate) async fn report_heartbeat() {
-  let response = http::default_client()
-    .post("http://cmdb-debug-server/v1/heartbeat")
-    .send()
-    .await;
-
-  match response {
-    Ok(response) => {
-      if response.status().is_success() {
-        log::info!("Success to report heartbeat to CMDB server")
-      } else {
-        log::error!(
-          "Failed to report heartbeat to CMDB server, who answers HTTP status: {}",
-          response.status()
-        )
+use crate::collect::outbox;
+use crate::collect::outbox::Entry;
+use crate::collect::outbox::Outbox;
+use crate::collect::outbox::Outboxes;
+use crate::collect::outbox::ReportKind;
+use crate::collect::sink::file::FileSink;
+use crate::collect::sink::http::HttpSink;
+use crate::collect::sink::mqtt::MqttSink;
+use crate::collect::sink::nats::NatsSink;
+use crate::collect::sink::stdout::StdoutSink;
+use crate::collect::sink::unix::UnixSink;
+use crate::collect::sink::Delivery;
+use crate::collect::sink::Sink;
+use crate::collect::state;
+use crate::collect::state::ReportState;
+use crate::config::Config;
+use crate::config::SinkType;
+use crate::config::TargetConfig;
+use crate::identity::enroll::Enroller;
+use crate::identity::Identity;
+use crate::schema::Heartbeat;
+use crate::schema::LastRun;
+use crate::schema::MachineInfo;
+use crate::schema::RunStatus;
+use crate::support::kube;
+
+/// What became of a report, from the worst to the best.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+  Failed,
+  Queued,
+  Delivered,
+}
+
+/// Where the reports go, along with its configuration.
+struct Target {
+  config: TargetConfig,
+  sink:   Box<dyn Sink>,
+}
+
+/// Reports to the targets with the sinks built from a given configuration.
+///
+/// A reporter is immutable: reloading the configuration builds a new one, while the runs holding
+/// the previous one finish with it.
+pub(crate) struct Reporter {
+  config:   Arc<Config>,
+  /// Never empty.
+  targets:  Vec<Target>,
+  outboxes: Outboxes,
+  state:    Arc<ReportState>,
+  identity: Option<Arc<Identity>>,
+  enroller: Arc<Enroller>,
+}
+
+impl Reporter {
+  pub(crate) fn new(
+    config: Arc<Config>,
+    outboxes: Outboxes,
+    state: Arc<ReportState>,
+  ) -> std::io::Result<Self> {
+    let identity = if *config.signing().ed25519() {
+      Some(Arc::new(Identity::load_or_generate(config.state_dir())?))
+    } else {
+      None
+    };
+    let enroller = Arc::new(Enroller::new(config.clone(), identity.clone())?);
+    let mut targets = vec![];
+    for target in config.targets() {
+      let sink: Box<dyn Sink> = match target.sink() {
+        SinkType::Http => Box::new(HttpSink::new(
+          &config,
+          target.clone(),
+          identity.clone(),
+          enroller.clone(),
+          state.clone(),
+        )?),
+        SinkType::Stdout => Box::new(StdoutSink),
+        SinkType::File => Box::new(FileSink::new(
+          sink_path(&target)?,
+          *target.max_bytes(),
+          *target.max_files(),
+        )),
+        SinkType::Unix => Box::new(UnixSink::new(sink_path(&target)?)),
+        SinkType::Mqtt => Box::new(MqttSink::new(&config, target.clone(), identity.clone())),
+        SinkType::Nats => Box::new(NatsSink::new(&config, target.clone(), identity.clone())),
+      };
+      targets.push(Target {
+        config: target,
+        sink,
+      });
+    }
+    Ok(Self {
+      config,
+      targets,
+      outboxes,
+      state,
+      identity,
+      enroller,
+    })
+  }
+
+  pub(crate) fn config(&self) -> &Config {
+    &self.config
+  }
+
+  /// Key pair of the agent, if it signs with one.
+  pub(crate) fn identity(&self) -> Option<&Arc<Identity>> {
+    self.identity.as_ref()
+  }
+
+  pub(crate) fn enroller(&self) -> &Enroller {
+    &self.enroller
+  }
+
+  /// Enroll again for a new credential.
+  pub(crate) async fn rotate_credential(&self) -> std::io::Result<()> {
+    self.enroller.rotate().await
+  }
+
+  pub(crate) async fn report_heartbeat(&self) {
+    let mut heartbeat = Heartbeat::default();
+    heartbeat.set_agent_id(self.enroller.agent_id());
+    heartbeat.set_hostname(collect::host::get_hostname().unwrap_or_default());
+    heartbeat.set_version(env!("CARGO_PKG_VERSION").to_string());
+    heartbeat.set_uptime(self.state.uptime().as_secs());
+    heartbeat.set_state(self.state.agent_state());
+    heartbeat.set_timestamp(Some(SystemTime::now()));
+    heartbeat.set_last_collection(self.state.last_collection());
+    heartbeat.set_last_report(self.state.last_report());
+    heartbeat.set_inventory_hash(self.state.inventory_hash(&[]));
+    heartbeat.set_kubernetes(kube::get_kubernetes());
+    heartbeat.set_command_acks(self.state.commands().take_acks());
+
+    match serde_json::to_value(heartbeat) {
+      Ok(body) => {
+        let id = self.state.next_report_id().await;
+        self.report(Entry::new(ReportKind::Heartbeat, id, Some(body))).await;
       }
+      Err(e) => log::error!("Failed to serialize heartbeat: {}", e),
     }
-    Err(e) => {
-      log::error!(
-        "Failed to report heartbeat to CMDB server: {}",
-        e.to_string()
-      );
+  }
+
+  pub(crate) async fn report_machine_info(&self) {
+    let disabled = self.config.inventory().disabled();
+    let machine = self.collect_machine_info().await;
+    if let Err(e) = machine {
+      log::error!("Failed to collect machine info: {}", e);
+      self
+        .state
+        .record_collection(LastRun::new(RunStatus::Failed, Some(e.to_string())));
+      return;
+    }
+    let machine = machine.unwrap();
+    self.state.record_collection(LastRun::new(RunStatus::Success, None));
+
+    let mut body = match serde_json::to_value(machine) {
+      Ok(body) => body,
+      Err(e) => {
+        log::error!("Failed to serialize machine info: {}", e);
+        return;
+      }
+    };
+
+    // Sections left out of the collection are left out of the report, rather than sent empty.
+    if let Some(sections) = body.as_object_mut() {
+      sections.retain(|section, _| !disabled.contains(section));
+    }
+
+    let inventory = self.config.inventory();
+    let hash = state::content_hash(&body);
+    if !self.state.collect_inventory(&hash, &body, *inventory.max_age()) && *inventory.change_only()
+    {
+      log::debug!("Machine info is unchanged ({}), skip reporting it", hash);
+      return;
+    }
+
+    let entry = Entry::new(
+      ReportKind::Inventory,
+      self.state.next_report_id().await,
+      Some(body),
+    );
+    let status = match self.report(entry).await {
+      Outcome::Delivered => RunStatus::Success,
+      Outcome::Queued => RunStatus::Queued,
+      Outcome::Failed => RunStatus::Failed,
+    };
+    self.state.record_report(LastRun::new(status, None));
+  }
+
+  /// Collect the machine info on a blocking thread, which the run can give up on while it is stuck,
+  /// e.g. reading `/dev/mem`. The collections fail until a stuck one is done, rather than piling
+  /// up.
+  async fn collect_machine_info(&self) -> std::io::Result<MachineInfo> {
+    if !self.state.start_collection() {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::WouldBlock,
+        "the previous collection is still going",
+      ));
+    }
+    let disabled = self.config.inventory().disabled().clone();
+    let collecting = Collecting(self.state.clone());
+    tokio::task::spawn_blocking(move || {
+      let _collecting = collecting;
+      collect::collect_machine_info(&disabled)
+    })
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+  }
+
+  /// Report along every route, telling the worst outcome.
+  async fn report(&self, entry: Entry) -> Outcome {
+    let mut worst = Outcome::Delivered;
+    for route in outbox::routes(&self.config) {
+      worst = worst.min(self.report_along(route.as_deref(), &entry).await);
+    }
+    worst
+  }
+
+  /// Deliver the report, or queue it in the outbox of the route when the server is unavailable.
+  /// Reports are queued as well while earlier ones are waiting, so that they reach the server in
+  /// order.
+  async fn report_along(&self, route: Option<&str>, entry: &Entry) -> Outcome {
+    let outbox = self.outboxes.get(&route.map(str::to_string));
+    if let Some(outbox) = outbox.filter(|outbox| !outbox.is_empty()) {
+      return self.queue(outbox, entry).await;
+    }
+
+    let (target, delivery) = self.deliver(route, entry).await;
+    log_delivery(&target, entry.kind, &delivery);
+    match (outbox, delivery) {
+      (_, Delivery::Delivered) => Outcome::Delivered,
+      (Some(outbox), delivery) if delivery.is_transient() => self.queue(outbox, entry).await,
+      _ => Outcome::Failed,
     }
   }
-}
 
-pub(crate) async fn report_machine_info() {
-  let machine = collect::get_machine_info();
-  if let Err(e) = machine {
-    log::error!("Failed to collect machine info: {}", e);
-    return;
-  }
-
-  let response = http::default_client()
-    .post("http://cmdb-debug-server/v1/heartbeat")
-    .json(&machine.unwrap())
-    .send()
-    .await;
-
-  match response {
-    Ok(response) => {
-      if response.status().is_success() {
-        log::info!("Success to report machine info to CMDB server")
-      } else {
-        log::error!(
-          "Failed to report machine info to CMDB server, who answers HTTP status: {}",
-          response.status()
-        )
+  async fn queue(&self, outbox: &Outbox, entry: &Entry) -> Outcome {
+    match outbox.push(entry, self.config.outbox()).await {
+      Ok(()) => {
+        log::info!(
+          "Queued the {} in the outbox, {} report(s) waiting",
+          entry.kind,
+          outbox.len()
+        );
+        Outcome::Queued
       }
+      Err(e) => {
+        log::error!("Failed to queue the {} in the outbox: {}", entry.kind, e);
+        Outcome::Failed
+      }
+    }
+  }
+
+  /// Send the report along the route: to the given target, or else to the first target taking it,
+  /// in order. Tells the name of the last target tried.
+  pub(crate) async fn deliver(&self, route: Option<&str>, entry: &Entry) -> (String, Delivery) {
+    if let Some(name) = route {
+      return match self.targets.iter().find(|target| target.config.name() == name) {
+        Some(target) => (name.to_string(), self.deliver_to(target, entry).await),
+        None => (name.to_string(), Delivery::NoTarget),
+      };
     }
-    Err(e) => {
-      log::error!(
-        "Failed to report machine info to CMDB server: {}",
-        e.to_string()
+
+    for (target, next) in self.targets.iter().zip(self.targets.iter().skip(1)) {
+      let delivery = self.deliver_to(target, entry).await;
+      if !delivery.is_transient() {
+        return (target.config.name().clone(), delivery);
+      }
+      log::warn!(
+        "Failed to report {} to {}, fail over to {}: {}",
+        entry.kind,
+        target.config.name(),
+        next.config.name(),
+        delivery
       );
     }
+    let last = self.targets.last().expect("a reporter has at least one target");
+    (
+      last.config.name().clone(),
+      self.deliver_to(last, entry).await,
+    )
+  }
+
+  /// Send the report to the target, recording how it turned out. The report is held while the
+  /// circuit breaker of the target is open.
+  async fn deliver_to(&self, target: &Target, entry: &Entry) -> Delivery {
+    if let Some(remaining) = self.state.hold(target.config.name()) {
+      return Delivery::Held(remaining);
+    }
+
+    let sections = target.config.sections();
+    let body = match (entry.kind, &entry.body) {
+      (ReportKind::Inventory, Some(body)) => Some(state::select_sections(body, sections)),
+      (ReportKind::Heartbeat, Some(serde_json::Value::Object(body))) if !sections.is_empty() => {
+        // The target only knows the hash of the sections it receives.
+        let mut body = body.clone();
+        body.insert(
+          "inventoryHash".to_string(),
+          self.state.inventory_hash(sections).into(),
+        );
+        Some(body.into())
+      }
+      (_, body) => body.clone(),
+    };
+
+    let delivery = target.sink.deliver(entry.kind, entry.id.as_ref(), body).await;
+    let breaker = self.config.http().circuit_breaker();
+    self.state.record_delivery(target.config.name(), &delivery, breaker);
+    // Replayed from the outbox or not, the inventory counts as reported once a target has it.
+    if let (Delivery::Delivered, ReportKind::Inventory, Some(body)) =
+      (&delivery, entry.kind, &entry.body)
+    {
+      self.state.report_inventory(&state::content_hash(body)).await;
+    }
+    delivery
+  }
+}
+
+/// Ends the collection when dropped by the collecting thread, even if it panics.
+struct Collecting(Arc<ReportState>);
+
+impl Drop for Collecting {
+  fn drop(&mut self) {
+    self.0.finish_collection();
+  }
+}
+
+/// The path of a file or Unix domain socket sink, which is required.
+fn sink_path(target: &TargetConfig) -> std::io::Result<PathBuf> {
+  target.path().clone().ok_or_else(|| {
+    std::io::Error::new(
+      std::io::ErrorKind::InvalidInput,
+      format!("target {} has no path", target.name()),
+    )
+  })
+}
+
+fn log_delivery(target: &str, kind: ReportKind, delivery: &Delivery) {
+  match delivery {
+    Delivery::Delivered => log::info!("Success to report {} to {}", kind, target),
+    Delivery::Answered(status) => log::error!(
+      "Failed to report {} to CMDB server {}, who answers HTTP status: {}",
+      kind,
+      target,
+      status
+    ),
+    Delivery::Held(_) => log::warn!("Held the {} to {}: {}", kind, target, delivery),
+    delivery => log::error!("Failed to report {} to {}: {}", kind, target, delivery),
   }
 }
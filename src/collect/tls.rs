@@ -0,0 +1,349 @@
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustls::client::ResolvesClientCert;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::client::WebPkiVerifier;
+use rustls::sign::CertifiedKey;
+use rustls::Certificate;
+use rustls::ClientConfig;
+use rustls::OwnedTrustAnchor;
+use rustls::PrivateKey;
+use rustls::RootCertStore;
+use rustls::ServerName;
+use rustls::SignatureScheme;
+use sha2::Digest;
+
+use crate::config::TlsConfig;
+
+const PIN_PREFIX: &str = "sha256/";
+
+/// Build the TLS configuration of the HTTP client.
+///
+/// Certificates are verified against the CA bundles, then against the pinned public keys. The CA
+/// bundles and the client certificate are read again on the next handshake after they change.
+pub(crate) fn client_config(config: &TlsConfig) -> Result<ClientConfig> {
+  let verifier = PinningVerifier {
+    roots: Reloading::new(config.ca_files().clone(), {
+      let builtin_roots = *config.builtin_roots();
+      move |paths| load_roots(paths, builtin_roots)
+    })?,
+    pins:  config.pins().iter().map(|pin| parse_pin(pin)).collect::<Result<_>>()?,
+  };
+
+  let builder = ClientConfig::builder()
+    .with_safe_defaults()
+    .with_custom_certificate_verifier(Arc::new(verifier));
+
+  match (config.client_cert(), config.client_key()) {
+    (Some(cert), Some(key)) => {
+      let resolver = ClientCertResolver {
+        key: Reloading::new(vec![cert.clone(), key.clone()], |paths| {
+          load_certified_key(&paths[0], &paths[1])
+        })?,
+      };
+      Ok(builder.with_client_cert_resolver(Arc::new(resolver)))
+    }
+    (None, None) => Ok(builder.with_no_client_auth()),
+    _ => Err(Error::new(
+      ErrorKind::InvalidInput,
+      "client_cert and client_key must be given together",
+    )),
+  }
+}
+
+/// Parse a `sha256/<base64>` pin into the digest it holds.
+pub(crate) fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+  pin
+    .strip_prefix(PIN_PREFIX)
+    .and_then(|digest| STANDARD.decode(digest).ok())
+    .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+    .ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid pin `{}`, expected {}<base64>", pin, PIN_PREFIX),
+      )
+    })
+}
+
+type Load<T> = Box<dyn Fn(&[PathBuf]) -> Result<T> + Send + Sync>;
+
+/// A value loaded from files, loaded again when any of them is modified.
+///
+/// Files being replaced while they are read, a failed reload keeps the current value and is tried
+/// again on the next change.
+struct Reloading<T> {
+  paths:   Vec<PathBuf>,
+  load:    Load<T>,
+  current: RwLock<(Vec<Option<SystemTime>>, Arc<T>)>,
+}
+
+impl<T> Reloading<T> {
+  fn new<F>(paths: Vec<PathBuf>, load: F) -> Result<Self>
+  where
+    F: Fn(&[PathBuf]) -> Result<T> + Send + Sync + 'static,
+  {
+    let modified = modified(&paths);
+    let value = load(&paths)?;
+    Ok(Self {
+      paths,
+      load: Box::new(load),
+      current: RwLock::new((modified, Arc::new(value))),
+    })
+  }
+
+  fn get(&self) -> Arc<T> {
+    let modified = modified(&self.paths);
+    {
+      let current = self.current.read().unwrap();
+      if current.0 == modified {
+        return current.1.clone();
+      }
+    }
+
+    let mut current = self.current.write().unwrap();
+    match (self.load)(&self.paths) {
+      Ok(value) => {
+        log::info!("Reloaded TLS files {:?}", self.paths);
+        *current = (modified, Arc::new(value));
+      }
+      Err(e) => {
+        log::warn!(
+          "Failed to reload TLS files {:?}, keep the current ones: {}",
+          self.paths,
+          e
+        );
+        current.0 = modified;
+      }
+    }
+    current.1.clone()
+  }
+}
+
+fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+  paths
+    .iter()
+    .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+    .collect()
+}
+
+/// Verifies the server certificate against the trusted CAs, then against the pinned public keys.
+struct PinningVerifier {
+  roots: Reloading<WebPkiVerifier>,
+  pins:  Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    server_name: &ServerName,
+    scts: &mut dyn Iterator<Item = &[u8]>,
+    ocsp_response: &[u8],
+    now: SystemTime,
+  ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+    let verified = self.roots.get().verify_server_cert(
+      end_entity,
+      intermediates,
+      server_name,
+      scts,
+      ocsp_response,
+      now,
+    )?;
+    if self.pins.is_empty() {
+      return Ok(verified);
+    }
+
+    let pinned = std::iter::once(end_entity)
+      .chain(intermediates)
+      .filter_map(|certificate| spki(&certificate.0))
+      .any(|spki| {
+        let digest: [u8; 32] = sha2::Sha256::digest(spki).into();
+        self.pins.contains(&digest)
+      });
+    if pinned {
+      Ok(verified)
+    } else {
+      Err(rustls::Error::General(
+        "server certificate matches none of the pinned public keys".to_string(),
+      ))
+    }
+  }
+}
+
+/// Presents the client certificate for mutual TLS.
+struct ClientCertResolver {
+  key: Reloading<CertifiedKey>,
+}
+
+impl ResolvesClientCert for ClientCertResolver {
+  fn resolve(
+    &self,
+    _acceptable_issuers: &[&[u8]],
+    _sigschemes: &[SignatureScheme],
+  ) -> Option<Arc<CertifiedKey>> {
+    Some(self.key.get())
+  }
+
+  fn has_certs(&self) -> bool {
+    true
+  }
+}
+
+fn load_roots(paths: &[PathBuf], builtin_roots: bool) -> Result<WebPkiVerifier> {
+  let mut roots = RootCertStore::empty();
+  if builtin_roots {
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+      OwnedTrustAnchor::from_subject_spki_name_constraints(
+        anchor.subject,
+        anchor.spki,
+        anchor.name_constraints,
+      )
+    }));
+  }
+  for path in paths {
+    for certificate in load_certificates(path)? {
+      roots
+        .add(&certificate)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    }
+  }
+
+  Ok(WebPkiVerifier::new(roots, None))
+}
+
+pub(crate) fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+  let mut reader = BufReader::new(std::fs::File::open(path)?);
+  let certificates = rustls_pemfile::certs(&mut reader)?;
+  if certificates.is_empty() {
+    return Err(Error::new(
+      ErrorKind::InvalidData,
+      format!("{}: no PEM certificate found", path.display()),
+    ));
+  }
+  Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+  let certificates = load_certificates(cert)?;
+
+  let mut reader = BufReader::new(std::fs::File::open(key)?);
+  let key = rustls_pemfile::read_all(&mut reader)?
+    .into_iter()
+    .find_map(|item| match item {
+      rustls_pemfile::Item::PKCS8Key(key)
+      | rustls_pemfile::Item::RSAKey(key)
+      | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+      _ => None,
+    })
+    .ok_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: no PEM private key found", key.display()),
+      )
+    })?;
+  let key =
+    rustls::sign::any_supported_type(&key).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+  Ok(CertifiedKey::new(certificates, key))
+}
+
+/// Extract the DER encoded SubjectPublicKeyInfo from a DER encoded X.509 certificate, which is the
+/// 7th field of the TBSCertificate, or the 6th one without the explicit version.
+fn spki(certificate: &[u8]) -> Option<&[u8]> {
+  let (_, certificate, _) = der_element(certificate)?;
+  let (_, mut tbs, _) = der_element(certificate)?;
+
+  if tbs.first() == Some(&0xa0) {
+    tbs = der_element(tbs)?.2;
+  }
+  // Serial number, signature algorithm, issuer, validity and subject.
+  for _ in 0..5 {
+    tbs = der_element(tbs)?.2;
+  }
+
+  let (header, content, _) = der_element(tbs)?;
+  Some(&tbs[..header + content.len()])
+}
+
+/// Split the first DER element off the input, returning the length of its header, its content and
+/// the remaining input.
+fn der_element(input: &[u8]) -> Option<(usize, &[u8], &[u8])> {
+  let first = *input.get(1)?;
+  let (header, length) = if first < 0x80 {
+    (2, first as usize)
+  } else {
+    let size = (first & 0x7f) as usize;
+    if size == 0 || size > std::mem::size_of::<usize>() {
+      return None;
+    }
+    let length = input
+      .get(2..2 + size)?
+      .iter()
+      .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+    (2 + size, length)
+  };
+
+  let content = input.get(header..header.checked_add(length)?)?;
+  Some((header, content, &input[header + length..]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBjTCCATOgAwIBAgIUFCVA71E4PT9W7wK4VjqCe5I4aEcwCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQY21kYi5leGFtcGxlLmNvbTAgFw0yNjEwMTcwNjQ2NTBaGA8y
+MTI2MDkyMzA2NDY1MFowGzEZMBcGA1UEAwwQY21kYi5leGFtcGxlLmNvbTBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABJvVuCRRQ1iewrBjil9c96hAGgj9JMaeeQo6
+yNb8eYn1MOrk/LOtTZzWNSG1HLsRYMeR8AJ9k/7/NinzCkZEmcGjUzBRMB0GA1Ud
+DgQWBBRjIeTBkWCLVomBwIEOVqYzlmirWjAfBgNVHSMEGDAWgBRjIeTBkWCLVomB
+wIEOVqYzlmirWjAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIHt+
+BSLRVQjwTjOXYxf1phGJPIWdkmNKXptkXvtkTBtVAiEAmBWDYT9pOPmfSWG+Thsd
+D/I4NQDhX1qQB8XrUBqGRKw=
+-----END CERTIFICATE-----
+";
+
+  #[test]
+  fn test_spki() {
+    let certificate = rustls_pemfile::certs(&mut CERTIFICATE.as_bytes()).unwrap().remove(0);
+    let digest: [u8; 32] = sha2::Sha256::digest(spki(&certificate).unwrap()).into();
+    assert_eq!(
+      digest,
+      parse_pin("sha256/h5GL0ptzJOyX60xDy4VNpPRpxIiTpDIPfIsfFSbwwN8=").unwrap()
+    );
+
+    assert!(spki(&certificate[..100]).is_none());
+    assert!(parse_pin("h5GL0ptzJOyX60xDy4VNpPRpxIiTpDIPfIsfFSbwwN8=").is_err());
+  }
+
+  #[test]
+  fn test_reloading() {
+    let path = std::env::temp_dir().join("cmdb-agent-test-reloading");
+    std::fs::write(&path, "first").unwrap();
+    let reloading = Reloading::new(vec![path.clone()], |paths| {
+      std::fs::read_to_string(&paths[0])
+    })
+    .unwrap();
+    assert_eq!(*reloading.get(), "first");
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::write(&path, "second").unwrap();
+    assert_eq!(*reloading.get(), "second");
+
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(*reloading.get(), "second");
+  }
+}
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use log::LevelFilter;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::agent::run_job_now;
+use crate::agent::SharedReporter;
+use crate::collect::http::Signer;
+use crate::collect::state::ReportState;
+use crate::collect::task::Reporter;
+use crate::config::layer::Loader;
+use crate::config::SigningConfig;
+use crate::schema::Action;
+use crate::schema::Command;
+use crate::schema::CommandAck;
+use crate::schema::CommandStatus;
+use crate::schema::Job;
+
+/// Longest a command may be valid for, from when it is received.
+const MAX_COMMAND_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Commands of the server waiting to be run, along with the outcome of the ones run, waiting for
+/// the next heartbeat.
+#[derive(Default)]
+pub(crate) struct Inbox {
+  received: Mutex<VecDeque<serde_json::Value>>,
+  /// IDs of the commands accepted, until they expire, so that a command the server sends again is
+  /// not run twice.
+  seen:     Mutex<VecDeque<(String, SystemTime)>>,
+  acks:     Mutex<Vec<CommandAck>>,
+  pushed:   Notify,
+}
+
+impl Inbox {
+  /// Queue the commands, the ones received before being left out once verified.
+  pub(crate) fn receive(&self, commands: Vec<serde_json::Value>) {
+    if commands.is_empty() {
+      return;
+    }
+    self.received.lock().unwrap().extend(commands);
+    self.pushed.notify_one();
+  }
+
+  /// Wait for the next command.
+  pub(crate) async fn next(&self) -> serde_json::Value {
+    loop {
+      if let Some(command) = self.received.lock().unwrap().pop_front() {
+        return command;
+      }
+      self.pushed.notified().await;
+    }
+  }
+
+  /// Verify the command, which is to be run unless it was run before. Its ID is only remembered
+  /// once its signature is verified, so that a forged command can not take the ID of a genuine
+  /// one, and until the command expires, after which it is rejected anyway.
+  pub(crate) fn accept(
+    &self,
+    signing: &SigningConfig,
+    command: &serde_json::Value,
+  ) -> std::result::Result<Option<Command>, String> {
+    let command = verify(signing, command, SystemTime::now())?;
+    let mut seen = self.seen.lock().unwrap();
+    let now = SystemTime::now();
+    seen.retain(|(_, expires_at)| *expires_at > now);
+    if seen.iter().any(|(seen, _)| seen == command.id()) {
+      return Ok(None);
+    }
+    seen.push_back((command.id().clone(), *command.expires_at()));
+    Ok(Some(command))
+  }
+
+  pub(crate) fn acknowledge(&self, ack: CommandAck) {
+    self.acks.lock().unwrap().push(ack);
+  }
+
+  /// Take the outcome of the commands run so far, for the heartbeat to carry them.
+  pub(crate) fn take_acks(&self) -> Vec<CommandAck> {
+    std::mem::take(&mut *self.acks.lock().unwrap())
+  }
+}
+
+/// Runs the commands of the server with the current reporter, one at a time as they arrive.
+pub(crate) struct Commander {
+  loader:    Loader,
+  reports:   Arc<ReportState>,
+  reporter:  SharedReporter,
+  /// Asks for the configuration to be reloaded, unless the agent can not reload it.
+  reload:    Option<UnboundedSender<String>>,
+  /// Level the agent started with, restored once a raised level expires.
+  log_level: LevelFilter,
+  restore:   Option<JoinHandle<()>>,
+}
+
+impl Commander {
+  pub(crate) fn new(
+    loader: Loader,
+    reports: Arc<ReportState>,
+    reporter: SharedReporter,
+    reload: Option<UnboundedSender<String>>,
+  ) -> Self {
+    Self {
+      loader,
+      reports,
+      reporter,
+      reload,
+      log_level: log::max_level(),
+      restore: None,
+    }
+  }
+
+  pub(crate) async fn run(mut self) {
+    loop {
+      let command = self.reports.commands().next().await;
+      let reporter = self.reporter.read().unwrap().clone();
+      let id = command.get("id").and_then(serde_json::Value::as_str).map(str::to_string);
+
+      let command = match self.reports.commands().accept(reporter.config().signing(), &command) {
+        Ok(Some(command)) => command,
+        Ok(None) => {
+          log::debug!(
+            "Ignored the command {} of the server, which ran already",
+            id.as_deref().unwrap_or_default()
+          );
+          continue;
+        }
+        Err(reason) => {
+          log::warn!(
+            "Ignored the command {} of the server: {}",
+            id.as_deref().unwrap_or("without ID"),
+            reason
+          );
+          if let Some(id) = id {
+            let ack = CommandAck::new(id, CommandStatus::Rejected, Some(reason));
+            self.reports.commands().acknowledge(ack);
+          }
+          continue;
+        }
+      };
+
+      log::info!(
+        "Run the command {} of the server: {:?}",
+        command.id(),
+        command.action()
+      );
+      let ack = match self.execute(&reporter, command.action()).await {
+        Ok(()) => CommandAck::new(command.id().clone(), CommandStatus::Done, None),
+        Err(e) => {
+          log::error!("Failed to run the command {}: {}", command.id(), e);
+          CommandAck::new(
+            command.id().clone(),
+            CommandStatus::Failed,
+            Some(e.to_string()),
+          )
+        }
+      };
+      self.reports.commands().acknowledge(ack);
+    }
+  }
+
+  async fn execute(&mut self, reporter: &Reporter, action: &Action) -> Result<()> {
+    match action {
+      // As a run of the inventory job, so that it does not run alongside a scheduled one.
+      Action::CollectNow => match run_job_now("inventory", &self.reporter, &self.reports).await {
+        true => Ok(()),
+        false => Err(Error::new(
+          ErrorKind::Other,
+          "the inventory job is running already",
+        )),
+      },
+      Action::SetSchedule { job, schedule } => {
+        let key = match job {
+          Job::Heartbeat => "schedule.heartbeat.every",
+          Job::Inventory => "schedule.inventory.every",
+        };
+        self.configure(key, schedule.clone())
+      }
+      Action::EnableCollectors { collectors } => {
+        let mut disabled = self.disabled_collectors()?;
+        disabled.retain(|collector| !collectors.contains(collector));
+        self.configure("inventory.disabled", serde_json::to_string(&disabled)?)
+      }
+      Action::DisableCollectors { collectors } => {
+        let mut disabled = self.disabled_collectors()?;
+        for collector in collectors {
+          if !disabled.contains(collector) {
+            disabled.push(collector.clone());
+          }
+        }
+        self.configure("inventory.disabled", serde_json::to_string(&disabled)?)
+      }
+      Action::RotateCredential => reporter.rotate_credential().await,
+      Action::SetLogLevel { level, duration } => {
+        let level = LevelFilter::from_str(level)
+          .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", level, e)))?;
+        self.raise_log_level(level, *duration);
+        Ok(())
+      }
+    }
+  }
+
+  /// The collectors disabled by the configuration as it is about to be reloaded, which may be ahead
+  /// of the one of the current reporter.
+  fn disabled_collectors(&self) -> Result<Vec<String>> {
+    let layered = self
+      .loader
+      .load()
+      .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(layered.config().inventory().disabled().clone())
+  }
+
+  /// Override the configuration value until the agent restarts, then reload the configuration. The
+  /// value is dropped if the configuration turns out invalid with it.
+  fn configure(&self, key: &str, value: String) -> Result<()> {
+    let reload = self.reload.as_ref().ok_or_else(|| {
+      Error::new(
+        ErrorKind::Unsupported,
+        "the configuration can not be reloaded",
+      )
+    })?;
+
+    let previous = self.loader.set_remote(key, Some(value));
+    if let Err(e) = self.loader.load() {
+      self.loader.set_remote(key, previous);
+      return Err(Error::new(ErrorKind::InvalidInput, e.to_string()));
+    }
+    let _ = reload.send("The CMDB server changed the configuration".to_string());
+    Ok(())
+  }
+
+  /// Log at the level for the duration, then at the level the agent started with. A level raised
+  /// again replaces the previous one, along with its duration.
+  fn raise_log_level(&mut self, level: LevelFilter, duration: Duration) {
+    if let Some(restore) = self.restore.take() {
+      restore.abort();
+    }
+
+    log::set_max_level(level);
+    log::info!("Log at level {} for {:?}", level, duration);
+    let original = self.log_level;
+    self.restore = Some(tokio::spawn(async move {
+      tokio::time::sleep(duration).await;
+      log::set_max_level(original);
+      log::info!("Log at level {} again", original);
+    }));
+  }
+}
+
+/// Check the signature of the command, then read it, and check that it has not expired by the
+/// given time. Tells why the command is rejected otherwise.
+fn verify(
+  signing: &SigningConfig,
+  command: &serde_json::Value,
+  now: SystemTime,
+) -> std::result::Result<Command, String> {
+  let field = |name: &str| command.get(name).and_then(serde_json::Value::as_str);
+  let (Some(key_id), Some(signature)) = (field("keyId"), field("signature")) else {
+    return Err("unsigned".to_string());
+  };
+  let signer =
+    Signer::new(signing, None).ok_or_else(|| "no signing key to verify it".to_string())?;
+
+  // Maps are sorted by key, which makes the JSON canonical.
+  let mut signed = command.clone();
+  if let Some(fields) = signed.as_object_mut() {
+    fields.remove("signature");
+  }
+  let bytes = serde_json::to_vec(&signed).map_err(|e| e.to_string())?;
+  if !signer.verify(key_id, &bytes, signature) {
+    return Err(format!("bad signature by key {}", key_id));
+  }
+
+  let command: Command =
+    serde_json::from_value(command.clone()).map_err(|e| format!("unknown command: {}", e))?;
+  if *command.expires_at() <= now {
+    return Err("expired".to_string());
+  }
+  if *command.expires_at() > now + MAX_COMMAND_LIFETIME {
+    return Err(format!("valid for longer than {:?}", MAX_COMMAND_LIFETIME));
+  }
+  Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sign(mut command: serde_json::Value, secret: &[u8]) -> serde_json::Value {
+    let mut mac: hmac::Hmac<sha2::Sha256> = hmac::Mac::new_from_slice(secret).unwrap();
+    hmac::Mac::update(&mut mac, &serde_json::to_vec(&command).unwrap());
+    let signature = hex::encode(hmac::Mac::finalize(mac).into_bytes());
+    command["signature"] = format!("SHA256={}", signature).into();
+    command
+  }
+
+  #[test]
+  fn test_verify() {
+    let signing: SigningConfig = toml::from_str("[active]\nid = \"k1\"\nsecret = \"key\"").unwrap();
+    let command = serde_json::json!({
+      "id": "c1",
+      "type": "set_log_level",
+      "level": "debug",
+      "duration": "10m",
+      "expiresAt": "2023-11-14T22:30:00Z",
+      "keyId": "k1",
+    });
+    // 2023-11-14T22:13:20Z
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    assert_eq!(verify(&signing, &command, now).unwrap_err(), "unsigned");
+
+    let mut signed = sign(command.clone(), b"key");
+    assert_eq!(
+      *verify(&signing, &signed, now).unwrap().action(),
+      Action::SetLogLevel {
+        level:    "debug".to_string(),
+        duration: Duration::from_secs(600),
+      }
+    );
+    // Replayed once expired, or valid for too long.
+    let later = now + Duration::from_secs(3600);
+    assert_eq!(verify(&signing, &signed, later).unwrap_err(), "expired");
+    let earlier = now - Duration::from_secs(7200);
+    assert!(verify(&signing, &signed, earlier).unwrap_err().starts_with("valid for longer"));
+    signed["level"] = "trace".into();
+    assert_eq!(
+      verify(&signing, &signed, now).unwrap_err(),
+      "bad signature by key k1"
+    );
+    assert!(verify(&signing, &sign(command.clone(), b"forged"), now).is_err());
+
+    let mut expired = command.clone();
+    expired["expiresAt"] = "2023-11-14T22:00:00Z".into();
+    assert_eq!(
+      verify(&signing, &sign(expired, b"key"), now).unwrap_err(),
+      "expired"
+    );
+
+    let mut unknown = command;
+    unknown["type"] = "reboot".into();
+    assert!(verify(&signing, &sign(unknown, b"key"), now)
+      .unwrap_err()
+      .starts_with("unknown command"));
+  }
+
+  #[test]
+  fn test_inbox() {
+    let signing: SigningConfig = toml::from_str("[active]\nid = \"k1\"\nsecret = \"key\"").unwrap();
+    let expires_at = SystemTime::now() + Duration::from_secs(600);
+    let command = serde_json::json!({
+      "id": "c1",
+      "type": "collect_now",
+      "expiresAt": humantime_serde::re::humantime::format_rfc3339_seconds(expires_at).to_string(),
+      "keyId": "k1",
+    });
+    let inbox = Inbox::default();
+
+    // A forged command taking the ID of a genuine one does not keep the latter from running.
+    assert!(inbox.accept(&signing, &sign(command.clone(), b"forged")).is_err());
+    assert!(inbox.accept(&signing, &command).is_err());
+    assert!(inbox.accept(&signing, &sign(command.clone(), b"key")).unwrap().is_some());
+    assert!(inbox.accept(&signing, &sign(command, b"key")).unwrap().is_none());
+
+    inbox.acknowledge(CommandAck::new("c1".to_string(), CommandStatus::Done, None));
+    assert_eq!(inbox.take_acks().len(), 1);
+    assert!(inbox.take_acks().is_empty());
+  }
+}
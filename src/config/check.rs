@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use toml::Table;
+use toml::Value;
+use toml_edit::ImDocument;
+use toml_edit::Item;
+
+use crate::collect::tls;
+use crate::config::error::Diagnostic;
+use crate::config::layer::Layered;
+use crate::config::layer::Source;
+use crate::config::secret::SECRET_KEYS;
+use crate::config::Config;
+use crate::config::Every;
+use crate::schema::MachineInfo;
+
+/// Parse a configuration file on its own, reporting syntax errors, unknown keys and values of the
+/// wrong type.
+pub(crate) fn parse_file(
+  path: &Path,
+  content: &str,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Table> {
+  let source = Source::File(path.to_path_buf());
+
+  let table = match toml::from_str::<Table>(content) {
+    Ok(table) => table,
+    Err(e) => {
+      diagnostics.push(Diagnostic {
+        source:   Some(source),
+        position: e.span().map(|span| position(content, span.start)),
+        key:      None,
+        message:  e.message().to_string(),
+      });
+      return None;
+    }
+  };
+
+  let mut unknown = vec![];
+  let result: Result<Config, _> =
+    serde_ignored::deserialize(toml::Deserializer::new(content), |path| {
+      unknown.push(path.to_string())
+    });
+  if let Err(e) = result {
+    diagnostics.push(Diagnostic {
+      source:   Some(source.clone()),
+      position: e.span().map(|span| position(content, span.start)),
+      key:      None,
+      message:  e.message().to_string(),
+    });
+  }
+  for key in unknown {
+    diagnostics.push(Diagnostic {
+      source:   Some(source.clone()),
+      position: locate(content, &key),
+      key:      Some(key),
+      message:  "unknown key".to_string(),
+    });
+  }
+
+  Some(table)
+}
+
+/// Check the values which are well typed but still unusable: cron expressions, URLs, pins and
+/// files or secrets out of reach. Each problem is located in the layer its value comes from.
+///
+/// The merged table is checked rather than the typed configuration, so that these problems are
+/// reported along with values of the wrong type elsewhere.
+pub(crate) fn validate(
+  layered: &Layered,
+  contents: &BTreeMap<PathBuf, String>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  let mut report = |key: &str, message: String| {
+    let source = layered.sources().get(key).cloned();
+    let position = match &source {
+      Some(Source::File(path)) => contents.get(path).and_then(|content| locate(content, key)),
+      _ => None,
+    };
+    diagnostics.push(Diagnostic {
+      source,
+      position,
+      key: Some(key.to_string()),
+      message,
+    });
+  };
+  let table = layered.table();
+
+  for job in ["schedule.heartbeat", "schedule.inventory"] {
+    let key = match get(table, job) {
+      Some(Value::Table(_)) => format!("{}.every", job),
+      _ => job.to_string(),
+    };
+    if let Some(every) = get(table, &key).and_then(Value::as_str) {
+      if let Err(e) = Every::from_str(every) {
+        report(&key, format!("invalid schedule: {}", e));
+      }
+    }
+  }
+
+  if let Some(endpoints) = get(table, "server.endpoints").and_then(Value::as_array) {
+    for message in check_endpoints(endpoints, &["http", "https"], "http(s)://host") {
+      report("server.endpoints", message);
+    }
+  }
+
+  let collectors = MachineInfo::collectors();
+  let disabled = get(table, "inventory.disabled").and_then(Value::as_array).map(Vec::as_slice);
+  for collector in disabled.unwrap_or_default().iter().filter_map(Value::as_str) {
+    if !collectors.iter().any(|known| known == collector) {
+      report(
+        "inventory.disabled",
+        format!(
+          "unknown collector `{}`, expected one of {}",
+          collector,
+          collectors.join(", ")
+        ),
+      );
+    }
+  }
+
+  let targets = get(table, "targets").and_then(Value::as_array).map(Vec::as_slice);
+  if get(table, "channel.enabled").and_then(Value::as_bool) == Some(true) {
+    // Without targets, the reports go to the `server`.
+    let http = targets.map_or(true, |targets| {
+      targets.is_empty()
+        || targets
+          .iter()
+          .any(|target| target.get("type").and_then(Value::as_str).unwrap_or("http") == "http")
+    });
+    if !http {
+      report(
+        "channel.enabled",
+        "the channel goes to the first HTTP target, and there is none".to_string(),
+      );
+    }
+  }
+  if let Some(path) = get(table, "channel.path").and_then(Value::as_str) {
+    if !path.starts_with('/') {
+      report(
+        "channel.path",
+        format!("invalid path `{}`, expected it to start with /", path),
+      );
+    }
+  }
+
+  if let Some(scheme) = get(table, "discovery.scheme").and_then(Value::as_str) {
+    if !matches!(scheme, "http" | "https") {
+      report(
+        "discovery.scheme",
+        format!("invalid scheme `{}`, expected http or https", scheme),
+      );
+    }
+  }
+
+  let sections = MachineInfo::sections();
+  let mut names = BTreeSet::new();
+  for target in targets.unwrap_or_default().iter().filter_map(Value::as_table) {
+    let name = target.get("name").and_then(Value::as_str).unwrap_or_default();
+    let valid =
+      !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+      report(
+        "targets",
+        format!(
+          "invalid target name `{}`, expected letters, digits, `-` or `_`",
+          name
+        ),
+      );
+    } else if !names.insert(name) {
+      report("targets", format!("duplicate target name `{}`", name));
+    }
+
+    let endpoints = target.get("endpoints").and_then(Value::as_array).map(Vec::as_slice);
+    let endpoints = endpoints.unwrap_or_default();
+    let credential_type = target.get("credentials").and_then(|credentials| credentials.get("type"));
+    let mut messages = match target.get("type").and_then(Value::as_str).unwrap_or("http") {
+      "http" => check_endpoints(endpoints, &["http", "https"], "http(s)://host"),
+      "file" | "unix" if target.get("path").is_none() => vec!["a path is required".to_string()],
+      "mqtt" => {
+        let mut messages = check_endpoints(endpoints, &["mqtt", "mqtts"], "mqtt(s)://host");
+        if endpoints.len() > 1 {
+          messages.push("a single MQTT broker endpoint is supported".to_string());
+        }
+        if credential_type.and_then(Value::as_str) == Some("bearer") {
+          messages.push("MQTT takes basic credentials only".to_string());
+        }
+        let pinned = get(table, "http.tls.pins").and_then(Value::as_array);
+        let tls = endpoints.iter().filter_map(Value::as_str).any(|url| url.starts_with("mqtts:"));
+        if tls && pinned.is_some_and(|pins| !pins.is_empty()) {
+          messages.push("the pinned public keys of http.tls do not apply to MQTT".to_string());
+        }
+        messages
+      }
+      "nats" => check_endpoints(endpoints, &["nats", "tls"], "nats://host or tls://host"),
+      _ => vec![],
+    };
+    if let Some(qos) = target.get("qos").and_then(Value::as_integer) {
+      if !(0..=2).contains(&qos) {
+        messages.push(format!("invalid QoS {}, expected 0, 1 or 2", qos));
+      }
+    }
+    for message in messages {
+      report("targets", format!("target `{}`: {}", name, message));
+    }
+    let wanted = target.get("sections").and_then(Value::as_array).map(Vec::as_slice);
+    for section in wanted.unwrap_or_default().iter().filter_map(Value::as_str) {
+      if !sections.iter().any(|known| known == section) {
+        report(
+          "targets",
+          format!(
+            "target `{}`: unknown section `{}`, expected one of {}",
+            name,
+            section,
+            sections.join(", ")
+          ),
+        );
+      }
+    }
+    for key in ["password", "token"] {
+      let secret = target.get("credentials").and_then(|credentials| credentials.get(key));
+      if let Some(message) = secret.and_then(unreachable_secret) {
+        report("targets", format!("target `{}`: {}", name, message));
+      }
+    }
+  }
+
+  if let Some(url) = get(table, "http.proxy.url").and_then(Value::as_str) {
+    match reqwest::Url::parse(url) {
+      Ok(url) if matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") => {}
+      Ok(_) => report(
+        "http.proxy.url",
+        format!(
+          "unsupported proxy `{}`, expected http(s):// or socks5(h)://",
+          url
+        ),
+      ),
+      Err(e) => report("http.proxy.url", format!("malformed URL `{}`: {}", url, e)),
+    }
+  }
+
+  let mut files: Vec<(&str, &str)> = vec![];
+  if let Some(ca_files) = get(table, "http.tls.ca_files").and_then(Value::as_array) {
+    files.extend(
+      ca_files
+        .iter()
+        .filter_map(Value::as_str)
+        .map(|path| ("http.tls.ca_files", path)),
+    );
+  }
+  for key in ["http.tls.client_cert", "http.tls.client_key"] {
+    files.extend(get(table, key).and_then(Value::as_str).map(|path| (key, path)));
+  }
+  for (key, path) in files {
+    if let Err(e) = std::fs::File::open(path) {
+      report(key, format!("unreadable file {}: {}", path, e));
+    }
+  }
+  if get(table, "http.tls.client_cert").is_some() != get(table, "http.tls.client_key").is_some() {
+    report(
+      "http.tls",
+      "client_cert and client_key must be given together".to_string(),
+    );
+  }
+  if let Some(pins) = get(table, "http.tls.pins").and_then(Value::as_array) {
+    for pin in pins.iter().filter_map(Value::as_str) {
+      if let Err(e) = tls::parse_pin(pin) {
+        report("http.tls.pins", e.to_string());
+      }
+    }
+  }
+
+  for key in SECRET_KEYS {
+    let file = format!("{}.file", key);
+    if let Some(path) = get(table, &file).and_then(Value::as_str) {
+      if let Err(e) = std::fs::File::open(path) {
+        report(&file, format!("unreadable secret file {}: {}", path, e));
+      }
+    }
+    let env = format!("{}.env", key);
+    if let Some(var) = get(table, &env).and_then(Value::as_str) {
+      if std::env::var_os(var).is_none() {
+        report(
+          &env,
+          format!("secret environment variable {} is not set", var),
+        );
+      }
+    }
+  }
+}
+
+/// Check the base URLs of a server, at least one of them being required.
+fn check_endpoints(endpoints: &[Value], schemes: &[&str], expected: &str) -> Vec<String> {
+  let mut messages = vec![];
+  if endpoints.is_empty() {
+    messages.push("at least one endpoint is required".to_string());
+  }
+  for endpoint in endpoints.iter().filter_map(Value::as_str) {
+    match reqwest::Url::parse(endpoint) {
+      Ok(url) if schemes.contains(&url.scheme()) && url.has_host() => {}
+      Ok(_) => messages.push(format!(
+        "unsupported URL `{}`, expected {}",
+        endpoint, expected
+      )),
+      Err(e) => messages.push(format!("malformed URL `{}`: {}", endpoint, e)),
+    }
+  }
+  messages
+}
+
+/// Tell why a secret read from a file or an environment variable is out of reach, if it is.
+fn unreachable_secret(secret: &Value) -> Option<String> {
+  if let Some(path) = secret.get("file").and_then(Value::as_str) {
+    let e = std::fs::File::open(path).err()?;
+    return Some(format!("unreadable secret file {}: {}", path, e));
+  }
+  let var = secret.get("env").and_then(Value::as_str)?;
+  std::env::var_os(var)
+    .is_none()
+    .then(|| format!("secret environment variable {} is not set", var))
+}
+
+/// Get the value at the dotted key path.
+fn get<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+  let (parents, last) = match key.rsplit_once('.') {
+    Some((parents, last)) => (Some(parents), last),
+    None => (None, key),
+  };
+
+  let mut table = table;
+  for segment in parents.into_iter().flat_map(|parents| parents.split('.')) {
+    table = table.get(segment)?.as_table()?;
+  }
+  table.get(last)
+}
+
+/// Find the position of the key at the dotted path in the TOML document.
+fn locate(content: &str, key: &str) -> Option<(usize, usize)> {
+  let document = ImDocument::parse(content).ok()?;
+
+  let mut item: &Item = document.as_item();
+  let mut span = None;
+  for segment in key.split('.') {
+    let Some((key, value)) = item.as_table_like().and_then(|table| table.get_key_value(segment))
+    else {
+      break;
+    };
+    span = key.span().or(span);
+    item = value;
+  }
+
+  span.map(|span| position(content, span.start))
+}
+
+/// Convert a byte offset into line and column, both starting from 1.
+fn position(content: &str, offset: usize) -> (usize, usize) {
+  let before = &content[..offset.min(content.len())];
+  let line = before.matches('\n').count() + 1;
+  let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+  (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_file() {
+    let content = "addr = \"0.0.0.0:8080\"\n\n[server]\nendpiont = \"http://cmdb\"\n\n[http]\nmax_retries = \"3\"\n";
+    let mut diagnostics = vec![];
+    let table = parse_file(Path::new("agent.toml"), content, &mut diagnostics);
+
+    assert!(table.is_some());
+    assert_eq!(diagnostics.len(), 2, "{:?}", diagnostics);
+    assert!(diagnostics
+      .iter()
+      .any(|d| d.key.as_deref() == Some("server.endpiont") && d.position == Some((4, 1))));
+    assert!(diagnostics.iter().any(|d| d.position == Some((7, 15))));
+  }
+
+  #[test]
+  fn test_parse_file_syntax_error() {
+    let mut diagnostics = vec![];
+    let table = parse_file(
+      Path::new("agent.toml"),
+      "[server]\nendpoints = [\n",
+      &mut diagnostics,
+    );
+
+    assert!(table.is_none());
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].position.is_some());
+  }
+
+  #[test]
+  fn test_parse_file_schedule() {
+    let mut diagnostics = vec![];
+    let content = "[schedule]\nheartbeat = \"30s\"\n\n[schedule.inventory]\nspaly = \"10m\"\n";
+    parse_file(Path::new("agent.toml"), content, &mut diagnostics);
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(
+      diagnostics[0].key.as_deref(),
+      Some("schedule.inventory.spaly")
+    );
+    assert_eq!(diagnostics[0].position, Some((5, 1)));
+
+    let mut diagnostics = vec![];
+    let content = "[schedule.inventory]\nevery = \"1h\"\nsplay = \"bogus\"\n";
+    parse_file(Path::new("agent.toml"), content, &mut diagnostics);
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(diagnostics[0].position, Some((3, 9)));
+  }
+
+  #[test]
+  fn test_parse_file_secret() {
+    let mut diagnostics = vec![];
+    let content = "[enrollment]\npath = \"/v1/enroll\"\ntoken = { flie = \"/token\" }\n";
+    parse_file(Path::new("agent.toml"), content, &mut diagnostics);
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert!(diagnostics[0].message.contains("flie"));
+    assert_eq!(diagnostics[0].position.map(|(line, _)| line), Some(3));
+  }
+
+  #[test]
+  fn test_locate() {
+    let content = "[http]\ntimeout = \"10s\"\n\n[http.credentials]\ntype = \"bearer\"\n  token = { file = \"/token\" }\n";
+    assert_eq!(locate(content, "http.timeout"), Some((2, 1)));
+    assert_eq!(locate(content, "http.credentials.token"), Some((6, 3)));
+    assert_eq!(
+      locate(content, "http.credentials.token.file"),
+      Some((6, 13))
+    );
+    assert_eq!(locate(content, "server.endpoints"), None);
+  }
+}
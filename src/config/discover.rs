@@ -0,0 +1,234 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::config::layer::Discovered;
+use crate::config::layer::Loader;
+use crate::config::layer::Source;
+use crate::config::DiscoveryConfig;
+use crate::config::DiscoverySource;
+use crate::support::kube;
+
+/// A server found by the discovery, as in a SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Record {
+  priority: u16,
+  weight:   u16,
+  host:     String,
+  port:     u16,
+}
+
+/// Finds the endpoints of the CMDB server, which replace `server.endpoints` in the configuration
+/// of the loader.
+pub(crate) struct Discoverer {
+  loader: Loader,
+}
+
+impl Discoverer {
+  pub(crate) fn new(loader: Loader) -> Self {
+    Self { loader }
+  }
+
+  /// Discover the endpoints once with the sources of the current configuration, telling whether
+  /// they changed. The endpoints found last are kept if none of the sources finds the server.
+  pub(crate) async fn discover(&self) -> bool {
+    let config = match self.loader.load() {
+      Ok(layered) => layered.config().discovery().clone(),
+      Err(_) => return false,
+    };
+
+    for source in config.sources() {
+      let found = match source {
+        DiscoverySource::Srv => srv(&config).await,
+        DiscoverySource::Kubernetes => kubernetes(&config),
+      };
+      match found {
+        Ok(Some((endpoints, from))) => {
+          let changed = self.loader.set_discovered(Some((endpoints.clone(), from.clone())));
+          if changed {
+            log::info!("The CMDB server is at {}, {}", endpoints.join(", "), from);
+          }
+          return changed;
+        }
+        Ok(None) => log::debug!("Discovery by {:?} found no CMDB server", source),
+        Err(e) => log::warn!("Failed to discover the CMDB server by {:?}: {}", source, e),
+      }
+    }
+    if !config.sources().is_empty() {
+      log::warn!("Discovered no CMDB server, keep the endpoints known so far");
+    }
+    false
+  }
+
+  /// Discover the endpoints again every `discovery.interval`, asking for the configuration to be
+  /// reloaded when they change.
+  pub(crate) async fn run(self, reload: UnboundedSender<String>) {
+    loop {
+      let interval = match self.loader.load() {
+        Ok(layered) => *layered.config().discovery().interval(),
+        Err(_) => *DiscoveryConfig::default().interval(),
+      };
+      tokio::time::sleep(interval).await;
+
+      if self.discover().await {
+        let _ = reload.send("The CMDB server moved".to_string());
+      }
+    }
+  }
+}
+
+/// The endpoints of the `_<service>._tcp` SRV records, in the order of RFC 2782.
+async fn srv(config: &DiscoveryConfig) -> Result<Option<Discovered>> {
+  // Without a domain, the relative name goes through the search domains.
+  let prefix = format!("_{}._tcp", config.service());
+  let name = match config.domain() {
+    Some(domain) => format!("{}.{}.", prefix, domain.trim_end_matches('.')),
+    None => prefix,
+  };
+  let resolver =
+    TokioAsyncResolver::tokio_from_system_conf().map_err(|e| Error::new(ErrorKind::Other, e))?;
+  let lookup = match resolver.srv_lookup(name.as_str()).await {
+    Ok(lookup) => lookup,
+    Err(e)
+      if matches!(
+        e.kind(),
+        hickory_resolver::error::ResolveErrorKind::NoRecordsFound { .. }
+      ) =>
+    {
+      return Ok(None)
+    }
+    Err(e) => return Err(Error::new(ErrorKind::Other, format!("{}: {}", name, e))),
+  };
+
+  let records = lookup
+    .iter()
+    .map(|srv| Record {
+      priority: srv.priority(),
+      weight:   srv.weight(),
+      host:     srv.target().to_utf8().trim_end_matches('.').to_string(),
+      port:     srv.port(),
+    })
+    // A single record of `.` tells the service is not available at the domain.
+    .filter(|record| !record.host.is_empty())
+    .collect::<Vec<_>>();
+  if records.is_empty() {
+    return Ok(None);
+  }
+
+  let endpoints = order(records, &mut rand::thread_rng())
+    .iter()
+    .map(|record| endpoint(config.scheme(), &record.host, record.port))
+    .collect();
+  Ok(Some((
+    endpoints,
+    Source::Discovery(format!("DNS SRV {}", name)),
+  )))
+}
+
+/// The endpoint of the Kubernetes service, from the environment variables Kubernetes sets in the
+/// pods of the namespace.
+fn kubernetes(config: &DiscoveryConfig) -> Result<Option<Discovered>> {
+  if !kube::is_under_kubernetes() {
+    return Ok(None);
+  }
+  let prefix = config.service().to_uppercase().replace('-', "_");
+  let host = std::env::var(format!("{}_SERVICE_HOST", prefix));
+  let port = std::env::var(format!("{}_SERVICE_PORT", prefix));
+  let (Ok(host), Ok(port)) = (host, port) else {
+    return Ok(None);
+  };
+  let port = port.parse().map_err(|e| {
+    Error::new(
+      ErrorKind::InvalidData,
+      format!("{}_SERVICE_PORT `{}`: {}", prefix, port, e),
+    )
+  })?;
+
+  let from = Source::Discovery(format!("Kubernetes service {}", config.service()));
+  Ok(Some((vec![endpoint(config.scheme(), &host, port)], from)))
+}
+
+fn endpoint(scheme: &str, host: &str, port: u16) -> String {
+  if host.contains(':') {
+    format!("{}://[{}]:{}", scheme, host, port)
+  } else {
+    format!("{}://{}:{}", scheme, host, port)
+  }
+}
+
+/// Order the records as RFC 2782 tells: by priority, then at random within a priority, a record
+/// being picked first in proportion to its weight.
+fn order<R: Rng>(mut records: Vec<Record>, rng: &mut R) -> Vec<Record> {
+  records.sort_by_key(|record| (record.priority, record.weight));
+
+  let mut ordered = Vec::with_capacity(records.len());
+  while !records.is_empty() {
+    let priority = records[0].priority;
+    let same = records.iter().take_while(|record| record.priority == priority).count();
+    let total: u32 = records[..same].iter().map(|record| u32::from(record.weight)).sum();
+
+    // Records of weight zero sort first and only get picked by a draw of zero.
+    let draw = rng.gen_range(0..=total);
+    let mut sum = 0;
+    let picked = records[..same]
+      .iter()
+      .position(|record| {
+        sum += u32::from(record.weight);
+        sum >= draw
+      })
+      .unwrap_or(same - 1);
+    ordered.push(records.remove(picked));
+  }
+  ordered
+}
+
+#[cfg(test)]
+mod tests {
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  use super::*;
+
+  fn record(priority: u16, weight: u16, host: &str) -> Record {
+    Record {
+      priority,
+      weight,
+      host: host.to_string(),
+      port: 443,
+    }
+  }
+
+  #[test]
+  fn test_order() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let records = vec![
+      record(20, 0, "backup"),
+      record(10, 0, "never-first"),
+      record(10, 100, "heavy"),
+    ];
+    let hosts =
+      |records: Vec<Record>| records.into_iter().map(|record| record.host).collect::<Vec<_>>();
+    assert_eq!(hosts(order(records.clone(), &mut rng))[2], "backup");
+
+    let mut first = 0;
+    for _ in 0..100 {
+      if hosts(order(records.clone(), &mut rng))[0] == "heavy" {
+        first += 1;
+      }
+    }
+    assert!(first >= 95, "heavy first {} times out of 100", first);
+  }
+
+  #[test]
+  fn test_endpoint() {
+    assert_eq!(
+      endpoint("https", "cmdb.example.com", 8443),
+      "https://cmdb.example.com:8443"
+    );
+    assert_eq!(endpoint("http", "fd00::1", 80), "http://[fd00::1]:80");
+  }
+}
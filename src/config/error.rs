@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::config::layer::Source;
+
+/// A problem found in the configuration, located as precisely as its source allows.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+  pub source:   Option<Source>,
+  /// Line and column, both starting from 1.
+  pub position: Option<(usize, usize)>,
+  /// Dotted key path of the offending value.
+  pub key:      Option<String>,
+  pub message:  String,
+}
+
+impl Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match (&self.source, &self.position) {
+      (Some(source), Some((line, column))) => write!(f, "{}:{}:{}: ", source, line, column)?,
+      (Some(source), None) => write!(f, "{}: ", source)?,
+      (None, _) => {}
+    }
+    if let Some(key) = &self.key {
+      write!(f, "`{}`: ", key)?;
+    }
+    write!(f, "{}", self.message)
+  }
+}
+
+/// The configuration is rejected, with every problem found in it.
+#[derive(Debug)]
+pub struct ConfigError {
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Error for ConfigError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    None
+  }
+}
+
+impl Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Found {} problem(s) in the configuration:",
+      self.diagnostics.len()
+    )?;
+    for diagnostic in &self.diagnostics {
+      write!(f, "\n  {}", diagnostic)?;
+    }
+    Ok(())
+  }
+}
+
+impl From<ConfigError> for std::io::Error {
+  fn from(value: ConfigError) -> Self {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, value)
+  }
+}
@@ -0,0 +1,425 @@
+//! Layered configuration.
+//!
+//! The effective configuration is merged from the following layers, each one overriding the
+//! previous ones:
+//!
+//! 1. Built-in defaults.
+//! 2. The configuration file, `/etc/cmdb/agent.toml` by default.
+//! 3. Drop-in files `*.toml` in the drop-in directory, `conf.d` next to the configuration file by
+//!    default, merged in lexical order of their file names.
+//! 4. Environment variables `CMDB_AGENT_<KEY>`, where nested keys are separated by `__`, e.g.
+//!    `CMDB_AGENT_SERVER__ENDPOINTS=http://cmdb-01,http://cmdb-02`.
+//! 5. The endpoints of the CMDB server found by `[discovery]`, if any.
+//! 6. Command line flags, e.g. `--addr`, `--endpoint` or `--set http.timeout=30s`.
+//! 7. Commands of the CMDB server, e.g. to change a schedule, until the agent restarts.
+//!
+//! Tables are merged key by key, while arrays and scalars are replaced as a whole.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use toml::Table;
+use toml::Value;
+
+use crate::config::check;
+use crate::config::error::ConfigError;
+use crate::config::error::Diagnostic;
+use crate::config::secret;
+use crate::config::Config;
+
+pub const ENV_PREFIX: &str = "CMDB_AGENT_";
+const ENV_KEY_SEPARATOR: &str = "__";
+const DROP_IN_DIR: &str = "conf.d";
+
+/// Where a configuration value comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+  Default,
+  File(PathBuf),
+  Env(String),
+  Cli,
+  /// Set by a command of the CMDB server, until the agent restarts.
+  Server,
+  /// Found by the discovery, e.g. `DNS SRV _cmdb._tcp.example.com`.
+  Discovery(String),
+}
+
+impl Display for Source {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Source::Default => write!(f, "default"),
+      Source::File(path) => write!(f, "{}", path.display()),
+      Source::Env(key) => write!(f, "env {}", key),
+      Source::Cli => write!(f, "command line"),
+      Source::Server => write!(f, "CMDB server"),
+      Source::Discovery(from) => write!(f, "discovered by {}", from),
+    }
+  }
+}
+
+/// Endpoints of the CMDB server, along with how they were discovered.
+pub type Discovered = (Vec<String>, Source);
+
+/// Loads the configuration from all of its layers.
+#[derive(Clone, Debug)]
+pub struct Loader {
+  file:       PathBuf,
+  dir:        PathBuf,
+  overrides:  Vec<(String, String)>,
+  /// Values set by the CMDB server, shared by the clones of the loader.
+  remote:     Arc<Mutex<BTreeMap<String, String>>>,
+  /// Endpoints of the CMDB server found by the discovery, shared by the clones of the loader.
+  discovered: Arc<Mutex<Option<Discovered>>>,
+}
+
+impl Loader {
+  pub fn new<P>(file: P) -> Self
+  where
+    P: AsRef<Path>,
+  {
+    let file = file.as_ref().to_path_buf();
+    let dir = file.parent().unwrap_or(Path::new(".")).join(DROP_IN_DIR);
+    Self {
+      file,
+      dir,
+      overrides: vec![],
+      remote: Default::default(),
+      discovered: Default::default(),
+    }
+  }
+
+  /// Use another drop-in directory instead of `conf.d` next to the configuration file.
+  pub fn dir<P>(mut self, dir: P) -> Self
+  where
+    P: AsRef<Path>,
+  {
+    self.dir = dir.as_ref().to_path_buf();
+    self
+  }
+
+  /// Override the value at the dotted key path, on top of every other layer.
+  pub fn set<K, V>(mut self, key: K, value: V) -> Self
+  where
+    K: Into<String>,
+    V: Into<String>,
+  {
+    self.overrides.push((key.into(), value.into()));
+    self
+  }
+
+  /// Set the value at the dotted key path on behalf of the CMDB server, on top of every other
+  /// layer, or unset it. Tells the value set before, if any.
+  pub fn set_remote(&self, key: &str, value: Option<String>) -> Option<String> {
+    let mut remote = self.remote.lock().unwrap();
+    match value {
+      Some(value) => remote.insert(key.to_string(), value),
+      None => remote.remove(key),
+    }
+  }
+
+  /// Set the endpoints of the CMDB server found by the discovery, below the command line, or
+  /// unset them. Tells whether they changed.
+  pub fn set_discovered(&self, discovered: Option<Discovered>) -> bool {
+    let mut current = self.discovered.lock().unwrap();
+    let changed = *current != discovered;
+    *current = discovered;
+    changed
+  }
+
+  pub fn file(&self) -> &Path {
+    &self.file
+  }
+
+  pub fn drop_in_dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Load the configuration, which is rejected with every problem found in any of its layers.
+  pub fn load(&self) -> Result<Layered, ConfigError> {
+    let mut layered = Layered::defaults();
+    let mut diagnostics = vec![];
+    let mut contents = BTreeMap::new();
+
+    let mut files = vec![self.file.clone()];
+    files.extend(self.drop_in_files(&mut diagnostics));
+    for file in files {
+      log::debug!("Merge configuration from {}", file.display());
+
+      let content = match std::fs::read_to_string(&file) {
+        Ok(content) => content,
+        Err(e) => {
+          diagnostics.push(Diagnostic {
+            source:   Some(Source::File(file)),
+            position: None,
+            key:      None,
+            message:  e.to_string(),
+          });
+          continue;
+        }
+      };
+      if let Some(table) = check::parse_file(&file, &content, &mut diagnostics) {
+        layered.merge(&file, table);
+      }
+      contents.insert(file, content);
+    }
+
+    let mut vars: Vec<(String, String)> =
+      std::env::vars().filter(|(key, _)| key.starts_with(ENV_PREFIX)).collect();
+    vars.sort();
+    for (key, value) in vars {
+      let path = key[ENV_PREFIX.len()..]
+        .split(ENV_KEY_SEPARATOR)
+        .map(|segment| segment.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(".");
+      layered.set(&path, &value, Source::Env(key));
+    }
+
+    if let Some((endpoints, source)) = self.discovered.lock().unwrap().clone() {
+      layered.set("server.endpoints", &endpoints.join(","), source);
+    }
+    for (key, value) in &self.overrides {
+      layered.set(key, value, Source::Cli);
+    }
+    for (key, value) in self.remote.lock().unwrap().iter() {
+      layered.set(key, value, Source::Server);
+    }
+
+    let reported = !diagnostics.is_empty();
+    check::validate(&layered, &contents, &mut diagnostics);
+    match Config::deserialize(Value::Table(layered.table.clone())) {
+      Ok(config) => layered.config = config,
+      // Values of the wrong type in files have been reported already.
+      Err(e) if !reported => diagnostics.push(Diagnostic {
+        source:   None,
+        position: None,
+        key:      None,
+        message:  e.to_string(),
+      }),
+      Err(_) => {}
+    }
+
+    if diagnostics.is_empty() {
+      Ok(layered)
+    } else {
+      Err(ConfigError { diagnostics })
+    }
+  }
+
+  fn drop_in_files(&self, diagnostics: &mut Vec<Diagnostic>) -> Vec<PathBuf> {
+    if !self.dir.is_dir() {
+      return vec![];
+    }
+
+    let entries = match std::fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(e) => {
+        diagnostics.push(Diagnostic {
+          source:   Some(Source::File(self.dir.clone())),
+          position: None,
+          key:      None,
+          message:  e.to_string(),
+        });
+        return vec![];
+      }
+    };
+
+    let mut files: Vec<PathBuf> = entries
+      .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+      .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+      .collect();
+    files.sort();
+    files
+  }
+}
+
+/// The merged configuration, along with the source of each value.
+#[derive(Clone, Debug)]
+pub struct Layered {
+  config:  Config,
+  table:   Table,
+  sources: BTreeMap<String, Source>,
+}
+
+impl Layered {
+  fn defaults() -> Self {
+    let config = Config::default();
+    let table = Table::try_from(&config)
+      .unwrap_or_else(|e| panic!("Failed to serialize the default configuration: {}", e));
+
+    let mut sources = BTreeMap::new();
+    for (key, _) in flatten(&table) {
+      sources.insert(key, Source::Default);
+    }
+
+    Self {
+      config,
+      table,
+      sources,
+    }
+  }
+
+  pub fn config(&self) -> &Config {
+    &self.config
+  }
+
+  pub fn sources(&self) -> &BTreeMap<String, Source> {
+    &self.sources
+  }
+
+  pub(crate) fn table(&self) -> &Table {
+    &self.table
+  }
+
+  /// Render the effective configuration as dotted TOML keys, each one annotated with its source.
+  /// Secrets given inline are redacted.
+  pub fn render(&self) -> String {
+    let mut output = String::new();
+    for (key, value) in flatten(&self.table) {
+      let source = self.sources.get(&key).cloned().unwrap_or(Source::Default);
+      let value = secret::redact_value(&key, value);
+      output.push_str(&format!("{} = {}  # {}\n", key, value, source));
+    }
+    output
+  }
+
+  fn merge(&mut self, path: &Path, table: Table) {
+    for (key, _) in flatten(&table) {
+      self.sources.insert(key, Source::File(path.to_path_buf()));
+    }
+    merge(&mut self.table, table);
+  }
+
+  fn set(&mut self, key: &str, raw: &str, source: Source) {
+    let path: Vec<&str> = key.split('.').collect();
+    let (last, parents) = path.split_last().unwrap();
+
+    let mut table = &mut self.table;
+    for parent in parents {
+      let entry = table.entry(parent.to_string()).or_insert_with(|| Value::Table(Table::new()));
+      if !entry.is_table() {
+        *entry = Value::Table(Table::new());
+      }
+      table = entry.as_table_mut().unwrap();
+    }
+
+    let value = parse_value(raw, table.get(*last));
+    table.insert(last.to_string(), value);
+
+    self.sources.retain(|k, _| k != key && !k.starts_with(&format!("{}.", key)));
+    self.sources.insert(key.to_string(), source);
+  }
+}
+
+/// Merge the overlay into the base table recursively.
+fn merge(base: &mut Table, overlay: Table) {
+  for (key, value) in overlay {
+    match (base.get_mut(&key), value) {
+      (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+      (_, value) => {
+        base.insert(key, value);
+      }
+    }
+  }
+}
+
+/// List the leaf values of the table by their dotted key paths.
+fn flatten(table: &Table) -> Vec<(String, Value)> {
+  let mut leaves = vec![];
+  for (key, value) in table {
+    match value {
+      Value::Table(table) => {
+        for (subkey, value) in flatten(table) {
+          leaves.push((format!("{}.{}", key, subkey), value));
+        }
+      }
+      _ => leaves.push((key.clone(), value.clone())),
+    }
+  }
+  leaves
+}
+
+/// Parse a raw string from the environment or the command line, guided by the type of the value it
+/// replaces: strings are taken verbatim, arrays may be given as comma separated lists, anything
+/// else is parsed as a TOML value and falls back to a string.
+fn parse_value(raw: &str, current: Option<&Value>) -> Value {
+  match current {
+    Some(Value::String(_)) => Value::String(raw.to_string()),
+    Some(Value::Array(array)) if !raw.trim_start().starts_with('[') => Value::Array(
+      raw
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| parse_value(item, array.first()))
+        .collect(),
+    ),
+    _ => toml::from_str::<Table>(&format!("value = {}", raw))
+      .ok()
+      .and_then(|mut table| table.remove("value"))
+      .unwrap_or_else(|| Value::String(raw.to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_merge() {
+    let mut base: Table =
+      toml::from_str("[server]\nendpoints = [\"a\"]\nheartbeat_path = \"/h\"").expect("Base table");
+    let overlay: Table = toml::from_str("[server]\nendpoints = [\"b\", \"c\"]").expect("Overlay");
+    merge(&mut base, overlay);
+
+    let server = base["server"].as_table().unwrap();
+    assert_eq!(server["endpoints"].as_array().unwrap().len(), 2);
+    assert_eq!(server["heartbeat_path"].as_str(), Some("/h"));
+  }
+
+  #[test]
+  fn test_parse_value() {
+    let string = Value::String(String::new());
+    let array = Value::Array(vec![string.clone()]);
+
+    assert_eq!(
+      parse_value("123", Some(&string)),
+      Value::String("123".to_string())
+    );
+    assert_eq!(
+      parse_value("5", Some(&Value::Integer(3))),
+      Value::Integer(5)
+    );
+    assert_eq!(parse_value("10s", None), Value::String("10s".to_string()));
+    assert_eq!(
+      parse_value("http://a, http://b", Some(&array)),
+      Value::Array(vec![
+        Value::String("http://a".to_string()),
+        Value::String("http://b".to_string()),
+      ])
+    );
+  }
+
+  #[test]
+  fn test_layered_set() {
+    let mut layered = Layered::defaults();
+    layered.set("http.max_retries", "7", Source::Cli);
+    layered.set(
+      "http.credentials.token",
+      "secret",
+      Source::Env("X".to_string()),
+    );
+
+    let config = Config::deserialize(Value::Table(layered.table.clone()));
+    assert!(config.is_err(), "Credentials without type");
+
+    layered.set("http.credentials.type", "bearer", Source::Cli);
+    let config = Config::deserialize(Value::Table(layered.table.clone())).expect("Config");
+    assert_eq!(*config.http().max_retries(), 7);
+    assert_eq!(layered.sources()["http.max_retries"], Source::Cli);
+  }
+}
@@ -0,0 +1,946 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use getset::Getters;
+use serde::de;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::MapAccess;
+use serde::de::Visitor;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use sha2::Digest;
+
+use crate::config::secret::Secret;
+
+pub mod check;
+pub mod discover;
+pub mod error;
+pub mod layer;
+pub mod secret;
+pub mod watch;
+
+const DEFAULT_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_CHANNEL_PATH: &str = "/v1/channel";
+const DEFAULT_ENDPOINT: &str = "http://cmdb-debug-server";
+const DEFAULT_DISCOVERY_SERVICE: &str = "cmdb";
+const DEFAULT_ENROLLMENT_PATH: &str = "/v1/enroll";
+const DEFAULT_HEARTBEAT_PATH: &str = "/v1/heartbeat";
+const DEFAULT_JOB_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_INVENTORY_PATH: &str = "/v1/heartbeat";
+const DEFAULT_SCHEDULE: &str = "*/5 * * * * *";
+const DEFAULT_STATE_DIR: &str = "/var/lib/cmdb-agent";
+const DEFAULT_TARGET: &str = "default";
+
+/// Configuration of the agent, loaded from `/etc/cmdb/agent.toml` by default.
+///
+/// Every section falls back to its defaults, so an empty file is a valid configuration.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct Config {
+  /// Address the local web server listens on.
+  #[getset(get = "pub")]
+  addr:       SocketAddr,
+  /// Directory the agent keeps its own files in, such as its key pair.
+  #[getset(get = "pub")]
+  state_dir:  PathBuf,
+  #[getset(get = "pub")]
+  server:     ServerConfig,
+  /// Servers the reports go to instead of `server`, see [`Config::targets`].
+  targets:    Vec<TargetConfig>,
+  #[getset(get = "pub")]
+  delivery:   DeliveryConfig,
+  #[getset(get = "pub")]
+  schedule:   ScheduleConfig,
+  #[getset(get = "pub")]
+  inventory:  InventoryConfig,
+  #[getset(get = "pub")]
+  http:       HttpConfig,
+  #[getset(get = "pub")]
+  signing:    SigningConfig,
+  #[getset(get = "pub")]
+  enrollment: EnrollmentConfig,
+  #[getset(get = "pub")]
+  outbox:     OutboxConfig,
+  #[getset(get = "pub")]
+  channel:    ChannelConfig,
+  #[getset(get = "pub")]
+  discovery:  DiscoveryConfig,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      addr:       DEFAULT_ADDR.parse().unwrap(),
+      state_dir:  PathBuf::from(DEFAULT_STATE_DIR),
+      server:     ServerConfig::default(),
+      targets:    vec![],
+      delivery:   DeliveryConfig::default(),
+      schedule:   ScheduleConfig::default(),
+      inventory:  InventoryConfig::default(),
+      http:       HttpConfig::default(),
+      signing:    SigningConfig::default(),
+      enrollment: EnrollmentConfig::default(),
+      outbox:     OutboxConfig::default(),
+      channel:    ChannelConfig::default(),
+      discovery:  DiscoveryConfig::default(),
+    }
+  }
+}
+
+impl Config {
+  /// The targets the reports go to: the configured ones, or else the `server` on its own.
+  pub fn targets(&self) -> Vec<TargetConfig> {
+    if !self.targets.is_empty() {
+      return self.targets.clone();
+    }
+    vec![TargetConfig {
+      name: DEFAULT_TARGET.to_string(),
+      endpoints: self.server.endpoints.clone(),
+      heartbeat_path: self.server.heartbeat_path.clone(),
+      inventory_path: self.server.inventory_path.clone(),
+      ..Default::default()
+    }]
+  }
+}
+
+/// Where the CMDB server lives.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct ServerConfig {
+  /// Base URLs of the CMDB server, tried in order until one of them answers.
+  #[getset(get = "pub")]
+  endpoints:      Vec<String>,
+  #[getset(get = "pub")]
+  heartbeat_path: String,
+  #[getset(get = "pub")]
+  inventory_path: String,
+}
+
+impl ServerConfig {
+  /// Join every endpoint with the given path.
+  pub fn urls(&self, path: &str) -> Vec<String> {
+    urls(&self.endpoints, path)
+  }
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    Self {
+      endpoints:      vec![DEFAULT_ENDPOINT.to_string()],
+      heartbeat_path: DEFAULT_HEARTBEAT_PATH.to_string(),
+      inventory_path: DEFAULT_INVENTORY_PATH.to_string(),
+    }
+  }
+}
+
+/// Where the reports go, a CMDB server or a local consumer, with its own sections of the inventory.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct TargetConfig {
+  /// Names the target in the logs, the status and the outbox directory.
+  #[getset(get = "pub")]
+  name:           String,
+  #[getset(get = "pub")]
+  #[serde(rename = "type")]
+  sink:           SinkType,
+  /// Base URLs of the CMDB server, tried in order until one of them answers.
+  #[getset(get = "pub")]
+  endpoints:      Vec<String>,
+  #[getset(get = "pub")]
+  heartbeat_path: String,
+  #[getset(get = "pub")]
+  inventory_path: String,
+  /// Credentials of the target, replacing `http.credentials` and the credential of the enrolled
+  /// agent.
+  #[getset(get = "pub")]
+  credentials:    Option<Credentials>,
+  /// Top-level sections of the inventory the target receives, e.g. `os` or `devices`, all of them
+  /// if empty. The hostname and the serial number, which identify the machine, are always sent.
+  #[getset(get = "pub")]
+  sections:       Vec<String>,
+  /// File or Unix domain socket the reports are written to.
+  #[getset(get = "pub")]
+  path:           Option<PathBuf>,
+  /// Size beyond which the file is rotated, never if zero.
+  #[getset(get = "pub")]
+  max_bytes:      u64,
+  /// Rotated files kept, `<path>.1` being the most recent.
+  #[getset(get = "pub")]
+  max_files:      usize,
+  /// Topic or subject the reports are published to, where `{hostname}`, `{serial}` and `{kind}`
+  /// are replaced, `{kind}` being `heartbeat` or `inventory`.
+  #[getset(get = "pub")]
+  topic:          Option<String>,
+  /// MQTT quality of service, 0 to 2.
+  #[getset(get = "pub")]
+  qos:            u8,
+  /// Whether the broker retains the last inventory, for new subscribers to get it at once.
+  #[getset(get = "pub")]
+  retain:         bool,
+  /// MQTT client ID, the hostname if unset.
+  #[getset(get = "pub")]
+  client_id:      Option<String>,
+}
+
+impl TargetConfig {
+  /// Join every endpoint with the given path.
+  pub fn urls(&self, path: &str) -> Vec<String> {
+    urls(&self.endpoints, path)
+  }
+}
+
+impl Default for TargetConfig {
+  fn default() -> Self {
+    Self {
+      name:           String::new(),
+      sink:           SinkType::Http,
+      endpoints:      vec![],
+      heartbeat_path: DEFAULT_HEARTBEAT_PATH.to_string(),
+      inventory_path: DEFAULT_INVENTORY_PATH.to_string(),
+      credentials:    None,
+      sections:       vec![],
+      path:           None,
+      max_bytes:      64 * 1024 * 1024,
+      max_files:      5,
+      topic:          None,
+      qos:            1,
+      retain:         true,
+      client_id:      None,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkType {
+  /// Posts to the CMDB server at `endpoints`.
+  #[default]
+  Http,
+  /// Writes NDJSON to the standard output.
+  Stdout,
+  /// Appends NDJSON to the file at `path`, rotated beyond `max_bytes`.
+  File,
+  /// Writes NDJSON to the Unix domain socket at `path`.
+  Unix,
+  /// Publishes to the MQTT broker at `endpoints`, on `topic`.
+  Mqtt,
+  /// Publishes to the NATS servers at `endpoints`, on `topic`.
+  Nats,
+}
+
+fn urls(endpoints: &[String], path: &str) -> Vec<String> {
+  endpoints
+    .iter()
+    .map(|endpoint| format!("{}{}", endpoint.trim_end_matches('/'), path))
+    .collect()
+}
+
+/// How the reports are spread over the targets.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct DeliveryConfig {
+  #[getset(get = "pub")]
+  mode: DeliveryMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+  /// Each report goes to the first target taking it, in order.
+  #[default]
+  Failover,
+  /// Each report goes to every target.
+  Fanout,
+}
+
+/// Schedules of the jobs of the agent.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct ScheduleConfig {
+  #[getset(get = "pub")]
+  heartbeat: JobConfig,
+  #[getset(get = "pub")]
+  inventory: JobConfig,
+}
+
+/// When a job runs, given in full or by its schedule alone:
+///
+/// ```toml
+/// heartbeat = "30s"
+/// inventory = { every = "0 0 * * * *", splay = "10m", run_at_startup = true, timeout = "2m" }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(remote = "Self", default)]
+pub struct JobConfig {
+  /// A cron expression with seconds, or an interval such as `30s` or `6h`, see [`Every`].
+  #[getset(get = "pub")]
+  every:          String,
+  /// Upper bound of the delay of the runs on a host, which is derived from the host name so that
+  /// the hosts do not all run the job at the same time.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  splay:          Duration,
+  /// Run the job once when the agent starts as well, after the delay of the host.
+  #[getset(get = "pub")]
+  run_at_startup: bool,
+  /// What becomes of a run while the previous one is still going.
+  #[getset(get = "pub")]
+  overlap:        Overlap,
+  /// Time after which a run is given up on, counted as timed out. The collection of the inventory
+  /// can not be stopped on its thread though: it goes on, and the next ones fail until it is done.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  timeout:        Duration,
+}
+
+impl Default for JobConfig {
+  fn default() -> Self {
+    Self {
+      every:          DEFAULT_SCHEDULE.to_string(),
+      splay:          Duration::ZERO,
+      run_at_startup: false,
+      overlap:        Overlap::default(),
+      timeout:        DEFAULT_JOB_TIMEOUT,
+    }
+  }
+}
+
+impl JobConfig {
+  /// The delay of the runs of the job on the host, the same on every run and spread evenly over
+  /// the splay across hosts.
+  pub fn delay(&self, host: &str, job: &str) -> Duration {
+    let digest = sha2::Sha256::digest(format!("{}/{}", host, job));
+    let draw = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    self.splay.mul_f64(draw as f64 / u64::MAX as f64)
+  }
+}
+
+// The table form goes through the derived implementation, which `remote = "Self"` turns into the
+// inherent `JobConfig::deserialize`, so that unknown keys and bad values are reported at their own
+// location rather than as a mismatch of the whole value.
+impl Serialize for JobConfig {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    JobConfig::serialize(self, serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for JobConfig {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    struct JobVisitor;
+
+    impl<'de> Visitor<'de> for JobVisitor {
+      type Value = JobConfig;
+
+      fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a schedule or a table of the job")
+      }
+
+      fn visit_str<E: de::Error>(self, every: &str) -> Result<JobConfig, E> {
+        Ok(JobConfig {
+          every: every.to_string(),
+          ..Default::default()
+        })
+      }
+
+      fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<JobConfig, A::Error> {
+        JobConfig::deserialize(MapAccessDeserializer::new(map))
+      }
+    }
+
+    deserializer.deserialize_any(JobVisitor)
+  }
+}
+
+/// What becomes of a run of a job while the previous one is still going.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overlap {
+  /// The run is skipped.
+  #[default]
+  Skip,
+  /// The run waits for the previous one to finish. A single run waits, the next ones are skipped.
+  Queue,
+}
+
+/// The schedule of a job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Every {
+  /// Runs one interval apart, from when the job is scheduled.
+  Interval(Duration),
+  /// A cron expression with seconds.
+  Cron(String),
+}
+
+impl FromStr for Every {
+  type Err = String;
+
+  fn from_str(every: &str) -> Result<Self, Self::Err> {
+    if let Ok(interval) = humantime_serde::re::humantime::parse_duration(every) {
+      if interval.is_zero() {
+        return Err("the interval is zero".to_string());
+      }
+      return Ok(Every::Interval(interval));
+    }
+    match cron::Schedule::from_str(every) {
+      Ok(_) => Ok(Every::Cron(every.to_string())),
+      Err(e) => Err(format!(
+        "`{}` is neither an interval such as `30s` nor a cron expression with seconds: {}",
+        every, e
+      )),
+    }
+  }
+}
+
+/// Reporting of the inventory, which is sent only when it changes unless it gets too old.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct InventoryConfig {
+  /// Send the inventory only when its content hash changes, the heartbeat carrying the hash.
+  #[getset(get = "pub")]
+  change_only: bool,
+  /// Age beyond which the inventory is sent again even if unchanged.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  max_age:     Duration,
+  /// Send a JSON Patch from the inventory last acknowledged by the server instead of the whole
+  /// one.
+  #[getset(get = "pub")]
+  delta:       bool,
+  /// Sections of the inventory left out of the collection, e.g. `networks`.
+  #[getset(get = "pub")]
+  disabled:    Vec<String>,
+}
+
+impl Default for InventoryConfig {
+  fn default() -> Self {
+    Self {
+      change_only: true,
+      max_age:     Duration::from_secs(60 * 60),
+      delta:       false,
+      disabled:    vec![],
+    }
+  }
+}
+
+/// Options of the HTTP client reporting to the CMDB server.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct HttpConfig {
+  /// Timeout of a whole request, e.g. `10s`.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  timeout:           Duration,
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  connect_timeout:   Duration,
+  /// Retries on transient failures.
+  #[getset(get = "pub")]
+  max_retries:       u32,
+  /// First delay between retries, doubled on every one, unless the server tells how long to wait.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  retry_backoff:     Duration,
+  /// Longest delay between retries. A server asking for a longer wait is not retried.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  max_retry_backoff: Duration,
+  #[getset(get = "pub")]
+  circuit_breaker:   CircuitBreakerConfig,
+  #[getset(get = "pub")]
+  credentials:       Option<Credentials>,
+  /// TLS settings, the built-in roots being trusted without them.
+  #[getset(get = "pub")]
+  tls:               Option<TlsConfig>,
+  /// Proxy settings, the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables being
+  /// honored without them.
+  #[getset(get = "pub")]
+  proxy:             Option<ProxyConfig>,
+  #[getset(get = "pub")]
+  compression:       CompressionConfig,
+}
+
+impl Default for HttpConfig {
+  fn default() -> Self {
+    Self {
+      timeout:           Duration::from_secs(10),
+      connect_timeout:   Duration::from_secs(5),
+      max_retries:       3,
+      retry_backoff:     Duration::from_secs(1),
+      max_retry_backoff: Duration::from_secs(30),
+      circuit_breaker:   Default::default(),
+      credentials:       None,
+      tls:               None,
+      proxy:             None,
+      compression:       Default::default(),
+    }
+  }
+}
+
+/// Circuit breaker of each target, which stops delivering to a target after consecutive transient
+/// failures, then lets a delivery through once `cooldown` has passed. The next failure opens it
+/// again, while a delivery the target answers closes it.
+///
+/// A server answering `429 Too Many Requests` or `503 Service Unavailable` with a `Retry-After`
+/// longer than the retries wait for opens it until then, enabled or not.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+  #[getset(get = "pub")]
+  enabled:  bool,
+  /// Consecutive transient failures opening the circuit.
+  #[getset(get = "pub")]
+  failures: u32,
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+  fn default() -> Self {
+    Self {
+      enabled:  true,
+      failures: 5,
+      cooldown: Duration::from_secs(60),
+    }
+  }
+}
+
+/// Trusted CAs, client certificate and pinned public keys of the HTTP client. The files are read
+/// again when they change, so that rotated certificates are picked up without restarting.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct TlsConfig {
+  /// PEM bundles of the CAs to trust.
+  #[getset(get = "pub")]
+  ca_files:      Vec<PathBuf>,
+  /// Trust the built-in Mozilla roots besides the CA bundles.
+  #[getset(get = "pub")]
+  builtin_roots: bool,
+  /// PEM certificate chain and private key for mutual TLS.
+  #[getset(get = "pub")]
+  client_cert:   Option<PathBuf>,
+  #[getset(get = "pub")]
+  client_key:    Option<PathBuf>,
+  /// Accepted server public keys, as `sha256/<base64>` digests of the SubjectPublicKeyInfo of any
+  /// certificate in the chain. Any public key is accepted if empty.
+  #[getset(get = "pub")]
+  pins:          Vec<String>,
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    Self {
+      ca_files:      vec![],
+      builtin_roots: true,
+      client_cert:   None,
+      client_key:    None,
+      pins:          vec![],
+    }
+  }
+}
+
+/// Proxy the requests go through, either an HTTP proxy tunneling with `CONNECT` or a SOCKS5 proxy.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+pub struct ProxyConfig {
+  /// `http://`, `https://`, `socks5://`, or `socks5h://` to resolve host names through the proxy.
+  #[getset(get = "pub")]
+  url:      String,
+  #[getset(get = "pub")]
+  username: Option<String>,
+  #[getset(get = "pub")]
+  password: Option<Secret>,
+  /// Hosts reached directly: host names, domains with a leading dot, IP addresses or CIDR blocks.
+  #[getset(get = "pub")]
+  #[serde(default)]
+  no_proxy: Vec<String>,
+}
+
+/// Compression of the request bodies, with the matching `Content-Encoding`. Servers which do not
+/// support it answer 415 Unsupported Media Type, and receive plain bodies from then on.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct CompressionConfig {
+  #[getset(get = "pub")]
+  algorithm: Compression,
+  /// Bodies smaller than this are sent as they are, e.g. heartbeats.
+  #[getset(get = "pub")]
+  min_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+  fn default() -> Self {
+    Self {
+      algorithm: Compression::None,
+      min_bytes: 1024,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+  #[default]
+  None,
+  Gzip,
+  Zstd,
+}
+
+/// Credentials sent along with every request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credentials {
+  Basic {
+    username: String,
+    password: Option<Secret>,
+  },
+  Bearer {
+    token: Secret,
+  },
+}
+
+/// Keys signing the requests with HMAC. Requests are signed with the active key, and signed again
+/// with the previous key if the server rejects the active one, so that keys can be rotated on the
+/// agents before the server.
+///
+/// Requests can be signed with the agent's own Ed25519 key as well, alongside HMAC or instead of it
+/// when there is no active key.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct SigningConfig {
+  #[getset(get = "pub")]
+  algorithm: SigningAlgorithm,
+  #[getset(get = "pub")]
+  active:    Option<SigningKey>,
+  #[getset(get = "pub")]
+  previous:  Option<SigningKey>,
+  /// Sign with the key pair of the agent, generated in the state directory on first start.
+  #[getset(get = "pub")]
+  ed25519:   bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SigningAlgorithm {
+  HmacSha1,
+  #[default]
+  HmacSha256,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+pub struct SigningKey {
+  /// Identifies the key to the server, which never sees the secret itself.
+  #[getset(get = "pub")]
+  id:     String,
+  #[getset(get = "pub")]
+  secret: Secret,
+}
+
+/// Enrollment of the agent, which trades a bootstrap token for an agent ID and a credential of its
+/// own, kept in the state directory and sent with every report instead of `http.credentials`.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct EnrollmentConfig {
+  #[getset(get = "pub")]
+  path:        String,
+  /// Short-lived token handed out to the machines to enroll. The agent does not enroll without it.
+  #[getset(get = "pub")]
+  token:       Option<Secret>,
+  /// First delay before enrolling again after a failure, doubled on every failure.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  backoff:     Duration,
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  max_backoff: Duration,
+}
+
+impl Default for EnrollmentConfig {
+  fn default() -> Self {
+    Self {
+      path:        DEFAULT_ENROLLMENT_PATH.to_string(),
+      token:       None,
+      backoff:     Duration::from_secs(10),
+      max_backoff: Duration::from_secs(5 * 60),
+    }
+  }
+}
+
+/// Spool of the reports which failed to be delivered, kept in the state directory to survive
+/// restarts, and replayed in order once the server is reachable again.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct OutboxConfig {
+  #[getset(get = "pub")]
+  enabled:     bool,
+  /// Total size of the queued reports in bytes, beyond which the oldest ones are dropped.
+  #[getset(get = "pub")]
+  max_bytes:   u64,
+  /// Age beyond which a queued report is dropped.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  max_age:     Duration,
+  /// First delay between replays while the server is unreachable, doubled on every failure.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  backoff:     Duration,
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  max_backoff: Duration,
+}
+
+impl Default for OutboxConfig {
+  fn default() -> Self {
+    Self {
+      enabled:     true,
+      max_bytes:   16 * 1024 * 1024,
+      max_age:     Duration::from_secs(24 * 60 * 60),
+      backoff:     Duration::from_secs(5),
+      max_backoff: Duration::from_secs(5 * 60),
+    }
+  }
+}
+
+/// WebSocket kept open to the CMDB server, which carries the heartbeats and lets the server push
+/// its commands at once instead of answering the next heartbeat with them.
+///
+/// The agent falls back to the scheduled HTTP jobs while the channel is down.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct ChannelConfig {
+  #[getset(get = "pub")]
+  enabled:       bool,
+  /// Path of the channel on the endpoints of the first HTTP target, over `ws` or `wss`.
+  #[getset(get = "pub")]
+  path:          String,
+  /// Interval of the pings, the channel being dropped when nothing came back for two of them.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  ping_interval: Duration,
+  /// First delay before connecting again, doubled on every failure.
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  backoff:       Duration,
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  max_backoff:   Duration,
+}
+
+impl Default for ChannelConfig {
+  fn default() -> Self {
+    Self {
+      enabled:       false,
+      path:          DEFAULT_CHANNEL_PATH.to_string(),
+      ping_interval: Duration::from_secs(30),
+      backoff:       Duration::from_secs(1),
+      max_backoff:   Duration::from_secs(5 * 60),
+    }
+  }
+}
+
+/// Discovery of the endpoints of the CMDB server, which replace `server.endpoints` once found.
+/// The sources are tried in order until one of them finds the server, again every `interval`.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+  /// None by default, `server.endpoints` being used as is.
+  #[getset(get = "pub")]
+  sources:  Vec<DiscoverySource>,
+  /// Domain of the `_cmdb._tcp.<domain>` SRV records, the search domains of the system resolver
+  /// being tried without it.
+  #[getset(get = "pub")]
+  domain:   Option<String>,
+  /// Kubernetes service of the CMDB server, found by its `<SERVICE>_SERVICE_HOST` and
+  /// `<SERVICE>_SERVICE_PORT` environment variables.
+  #[getset(get = "pub")]
+  service:  String,
+  /// Scheme of the discovered endpoints, `http` or `https`.
+  #[getset(get = "pub")]
+  scheme:   String,
+  #[getset(get = "pub")]
+  #[serde(with = "humantime_serde")]
+  interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+  fn default() -> Self {
+    Self {
+      sources:  vec![],
+      domain:   None,
+      service:  DEFAULT_DISCOVERY_SERVICE.to_string(),
+      scheme:   "https".to_string(),
+      interval: Duration::from_secs(5 * 60),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoverySource {
+  /// DNS SRV records, ordered by priority then weight.
+  Srv,
+  /// Environment variables of a Kubernetes service, when the agent runs in a pod.
+  Kubernetes,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_empty_config() {
+    let config: Config = toml::from_str("").expect("Empty config");
+    assert_eq!(config.addr().to_string(), DEFAULT_ADDR);
+    assert_eq!(
+      config.server().urls(config.server().heartbeat_path()),
+      vec!["http://cmdb-debug-server/v1/heartbeat".to_string()]
+    );
+    assert_eq!(config.schedule().heartbeat().every(), DEFAULT_SCHEDULE);
+  }
+
+  #[test]
+  fn test_full_config() {
+    let config: Config = toml::from_str(
+      r#"
+      addr = "127.0.0.1:9090"
+
+      [server]
+      endpoints = ["https://cmdb-01.example.com/", "https://cmdb-02.example.com"]
+
+      [schedule]
+      inventory = "0 */10 * * * *"
+
+      [http]
+      timeout = "30s"
+      max_retries = 5
+
+      [http.credentials]
+      type = "bearer"
+      token = "token"
+      "#,
+    )
+    .expect("Full config");
+
+    assert_eq!(config.addr().port(), 9090);
+    assert_eq!(
+      config.server().urls("/v1/heartbeat"),
+      vec![
+        "https://cmdb-01.example.com/v1/heartbeat".to_string(),
+        "https://cmdb-02.example.com/v1/heartbeat".to_string(),
+      ]
+    );
+    assert_eq!(config.schedule().heartbeat().every(), DEFAULT_SCHEDULE);
+    assert_eq!(config.schedule().inventory().every(), "0 */10 * * * *");
+    assert_eq!(*config.http().timeout(), Duration::from_secs(30));
+    assert_eq!(*config.http().max_retries(), 5);
+    assert!(matches!(
+      config.http().credentials(),
+      Some(Credentials::Bearer { .. })
+    ));
+  }
+
+  #[test]
+  fn test_schedule() {
+    let config: Config = toml::from_str(
+      r#"
+      [schedule]
+      heartbeat = "30s"
+
+      [schedule.inventory]
+      every = "0 0 * * * *"
+      splay = "10m"
+      run_at_startup = true
+      overlap = "queue"
+      "#,
+    )
+    .unwrap();
+    let heartbeat = config.schedule().heartbeat();
+    assert_eq!(
+      heartbeat.every().parse(),
+      Ok(Every::Interval(Duration::from_secs(30)))
+    );
+    assert_eq!(*heartbeat.splay(), Duration::ZERO);
+    assert!(!heartbeat.run_at_startup());
+    assert_eq!(*heartbeat.overlap(), Overlap::Skip);
+    assert_eq!(*heartbeat.timeout(), DEFAULT_JOB_TIMEOUT);
+
+    let inventory = config.schedule().inventory();
+    assert_eq!(
+      inventory.every().parse(),
+      Ok(Every::Cron("0 0 * * * *".to_string()))
+    );
+    assert!(*inventory.run_at_startup());
+    assert_eq!(*inventory.overlap(), Overlap::Queue);
+    assert_eq!(*inventory.timeout(), DEFAULT_JOB_TIMEOUT);
+    let delay = inventory.delay("node-01", "inventory");
+    assert!(delay < Duration::from_secs(600));
+    assert_eq!(delay, inventory.delay("node-01", "inventory"));
+    assert_ne!(delay, inventory.delay("node-02", "inventory"));
+
+    assert!("0s".parse::<Every>().is_err());
+    assert!("every day".parse::<Every>().is_err());
+  }
+
+  #[test]
+  fn test_targets() {
+    let config: Config = toml::from_str("").unwrap();
+    let targets = config.targets();
+    assert_eq!(targets.len(), 1);
+    assert_eq!(targets[0].name(), DEFAULT_TARGET);
+    assert_eq!(targets[0].endpoints(), config.server().endpoints());
+
+    let config: Config = toml::from_str(
+      r#"
+      [delivery]
+      mode = "fanout"
+
+      [[targets]]
+      name = "primary"
+      endpoints = ["https://cmdb.example.com"]
+
+      [[targets]]
+      name = "security"
+      endpoints = ["https://security.example.com/"]
+      inventory_path = "/v1/inventory"
+      sections = ["os", "networks"]
+      credentials = { type = "bearer", token = "token" }
+
+      [[targets]]
+      name = "local"
+      type = "file"
+      path = "/var/log/cmdb-agent/reports.ndjson"
+
+      [[targets]]
+      name = "edge"
+      type = "mqtt"
+      endpoints = ["mqtt://broker.example.com"]
+      qos = 0
+      "#,
+    )
+    .unwrap();
+    assert_eq!(*config.delivery().mode(), DeliveryMode::Fanout);
+    let targets = config.targets();
+    assert_eq!(targets.len(), 4);
+    assert_eq!(*targets[0].sink(), SinkType::Http);
+    assert_eq!(
+      targets[0].urls(targets[0].heartbeat_path()),
+      vec!["https://cmdb.example.com/v1/heartbeat".to_string()]
+    );
+    assert_eq!(
+      targets[1].urls(targets[1].inventory_path()),
+      vec!["https://security.example.com/v1/inventory".to_string()]
+    );
+    assert_eq!(targets[1].sections(), &["os", "networks"]);
+    assert!(targets[1].credentials().is_some());
+    assert_eq!(*targets[2].sink(), SinkType::File);
+    assert!(targets[2].path().is_some());
+    assert_eq!(*targets[3].sink(), SinkType::Mqtt);
+    assert_eq!(*targets[3].qos(), 0);
+    assert!(*targets[3].retain());
+    assert!(targets[3].topic().is_none());
+  }
+}
@@ -0,0 +1,189 @@
+use std::fmt::Debug;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::PathBuf;
+
+use serde::de;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::MapAccess;
+use serde::de::Visitor;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use toml::Value;
+
+/// Key paths of the secrets in the configuration, whose values are never printed. The elements of
+/// arrays of tables share the key path of the array.
+pub const SECRET_KEYS: &[&str] = &[
+  "enrollment.token",
+  "http.credentials.password",
+  "http.credentials.token",
+  "http.proxy.password",
+  "signing.active.secret",
+  "signing.previous.secret",
+  "targets.credentials.password",
+  "targets.credentials.token",
+];
+
+const REDACTED: &str = "<redacted>";
+
+/// A secret value, given inline, read from a file such as a mounted Kubernetes Secret, or read from
+/// an environment variable.
+///
+/// ```toml
+/// token = "inline"
+/// token = { file = "/var/run/secrets/cmdb/token" }
+/// token = { env = "CMDB_TOKEN" }
+/// ```
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum Secret {
+  Inline(String),
+  File { file: PathBuf },
+  Env { env: String },
+}
+
+/// The table form of a secret, whose unknown keys and bad values are reported at their own
+/// location rather than as a mismatch of the whole secret.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SecretTable {
+  file: Option<PathBuf>,
+  env:  Option<String>,
+}
+
+impl<'de> Deserialize<'de> for Secret {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    struct SecretVisitor;
+
+    impl<'de> Visitor<'de> for SecretVisitor {
+      type Value = Secret;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a secret, or a table of its `file` or `env`")
+      }
+
+      fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<Secret, E> {
+        Ok(Secret::Inline(value.to_string()))
+      }
+
+      fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<Secret, A::Error> {
+        match SecretTable::deserialize(MapAccessDeserializer::new(map))? {
+          SecretTable {
+            file: Some(file),
+            env: None,
+          } => Ok(Secret::File { file }),
+          SecretTable {
+            file: None,
+            env: Some(env),
+          } => Ok(Secret::Env { env }),
+          _ => Err(de::Error::custom("expected either `file` or `env`")),
+        }
+      }
+    }
+
+    deserializer.deserialize_any(SecretVisitor)
+  }
+}
+
+impl Secret {
+  /// Resolve the secret value. Files and environment variables are read on each call, so that
+  /// rotated secrets are picked up, with the trailing newline left by most editors trimmed.
+  pub fn expose(&self) -> Result<String> {
+    match self {
+      Secret::Inline(value) => Ok(value.clone()),
+      Secret::File { file } => {
+        let value = std::fs::read_to_string(file)?;
+        Ok(value.trim_end_matches(['\r', '\n']).to_string())
+      }
+      Secret::Env { env } => std::env::var(env).map_err(|e| {
+        Error::new(
+          ErrorKind::NotFound,
+          format!("environment variable {}: {}", env, e),
+        )
+      }),
+    }
+  }
+}
+
+impl Debug for Secret {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Secret::Inline(_) => write!(f, "Inline({})", REDACTED),
+      Secret::File { file } => f.debug_struct("File").field("file", file).finish(),
+      Secret::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+    }
+  }
+}
+
+/// Redact the value if the dotted key path holds a secret given inline.
+pub fn redact<'a>(key: &str, value: &'a str) -> &'a str {
+  if SECRET_KEYS.contains(&key) {
+    REDACTED
+  } else {
+    value
+  }
+}
+
+/// Redact the secrets given inline in the value at the dotted key path, including the ones nested
+/// in arrays of tables.
+pub fn redact_value(key: &str, value: Value) -> Value {
+  match value {
+    Value::String(value) => Value::String(redact(key, &value).to_string()),
+    Value::Array(array) => {
+      Value::Array(array.into_iter().map(|value| redact_value(key, value)).collect())
+    }
+    Value::Table(table) => Value::Table(
+      table
+        .into_iter()
+        .map(|(subkey, value)| {
+          let value = redact_value(&format!("{}.{}", key, subkey), value);
+          (subkey, value)
+        })
+        .collect(),
+    ),
+    value => value,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_expose() {
+    let path = std::env::temp_dir().join("cmdb-agent-test-secret");
+    std::fs::write(&path, "from-file\n").unwrap();
+    let secret = Secret::File { file: path.clone() };
+    assert_eq!(secret.expose().unwrap(), "from-file");
+    std::fs::remove_file(path).unwrap();
+
+    std::env::set_var("CMDB_AGENT_TEST_SECRET", "from-env");
+    let secret = Secret::Env {
+      env: "CMDB_AGENT_TEST_SECRET".to_string(),
+    };
+    assert_eq!(secret.expose().unwrap(), "from-env");
+
+    let secret = Secret::Env {
+      env: "CMDB_AGENT_TEST_SECRET_UNSET".to_string(),
+    };
+    assert!(secret.expose().is_err());
+  }
+
+  #[test]
+  fn test_redacted() {
+    let secret = Secret::Inline("b35057912c5def0e848b14fb".to_string());
+    assert!(!format!("{:?}", secret).contains("b35057912c5def0e848b14fb"));
+    assert_eq!(redact("signing.active.secret", "secret"), REDACTED);
+    assert_eq!(redact("http.timeout", "10s"), "10s");
+
+    let targets: toml::Table = toml::from_str(
+      r#"targets = [{ name = "dr", credentials = { type = "bearer", token = "secret" } }]"#,
+    )
+    .unwrap();
+    let targets = redact_value("targets", targets["targets"].clone());
+    assert_eq!(targets[0]["name"].as_str(), Some("dr"));
+    assert_eq!(targets[0]["credentials"]["token"].as_str(), Some(REDACTED));
+  }
+}
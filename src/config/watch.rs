@@ -0,0 +1,59 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use notify::event::EventKind;
+use notify::Event;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::config::layer::Loader;
+
+/// Watch the configuration file and the drop-in directory, sending the changed paths.
+///
+/// Directories are watched rather than files, so that editors replacing the file and Kubernetes
+/// swapping the `..data` symlink of a mounted ConfigMap are noticed as well. The watcher stops when
+/// it is dropped.
+pub fn watch(
+  loader: &Loader,
+  sender: UnboundedSender<PathBuf>,
+) -> notify::Result<RecommendedWatcher> {
+  let file = loader.file().to_path_buf();
+  let dir = loader.drop_in_dir().to_path_buf();
+  let parent = file.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+  let filter = {
+    let file = file.clone();
+    let dir = dir.clone();
+    move |path: &Path| {
+      path.file_name() == file.file_name()
+        || path.starts_with(&dir)
+        || path
+          .file_name()
+          .and_then(|name| name.to_str())
+          .is_some_and(|name| name.starts_with(".."))
+    }
+  };
+
+  let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+    Ok(event) => {
+      if matches!(event.kind, EventKind::Access(_)) {
+        return;
+      }
+      for path in event.paths.into_iter().filter(|path| filter(path)) {
+        let _ = sender.send(path);
+      }
+    }
+    Err(e) => log::warn!("Failed to watch configuration files: {}", e),
+  })?;
+
+  for path in [&parent, &dir] {
+    if path.is_dir() {
+      log::debug!("Watching {} for configuration changes", path.display());
+      watcher.watch(path, RecursiveMode::NonRecursive)?;
+    }
+  }
+
+  Ok(watcher)
+}
@@ -0,0 +1,218 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use reqwest_middleware::ClientWithMiddleware;
+use tokio::sync::Mutex;
+
+use crate::collect;
+use crate::collect::http;
+use crate::config::Config;
+use crate::config::SinkType;
+use crate::identity::Identity;
+use crate::schema::Enrollment;
+use crate::schema::EnrollmentRequest;
+use crate::schema::MachineInfo;
+use crate::support::fs::write_private;
+
+/// Name of the file holding the enrollment, in the state directory.
+const ENROLLMENT_FILE: &str = "enrollment.json";
+
+/// Enrolls the agent with the CMDB server and keeps the granted agent ID and credential.
+///
+/// The agent enrolls when it first reports, and again whenever the server rejects its credential.
+/// Concurrent reports wait for a single enrollment in progress, and the reports after a failed
+/// one go without enrolling until the backoff has passed.
+pub(crate) struct Enroller {
+  config:   Arc<Config>,
+  identity: Option<Arc<Identity>>,
+  /// Carries `http.credentials`, as the targets taking the enrollment have none of their own.
+  client:   ClientWithMiddleware,
+  path:     PathBuf,
+  current:  Mutex<Option<Enrollment>>,
+  /// When the agent may enroll again after failing to, and the delay waited for.
+  retry:    std::sync::Mutex<Option<(Instant, Duration)>>,
+}
+
+impl Enroller {
+  /// Pick up the enrollment kept in the state directory, if any.
+  pub(crate) fn new(config: Arc<Config>, identity: Option<Arc<Identity>>) -> Result<Self> {
+    let path = config.state_dir().join(ENROLLMENT_FILE);
+    let current = match std::fs::read(&path) {
+      Ok(content) => Some(
+        serde_json::from_slice::<Enrollment>(&content)
+          .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+      ),
+      Err(e) if e.kind() == ErrorKind::NotFound => None,
+      Err(e) => return Err(e),
+    };
+
+    let http = config.http();
+    let client = http::default_client(
+      http,
+      http.credentials().as_ref(),
+      config.signing(),
+      identity.clone(),
+    )?;
+    Ok(Self {
+      config,
+      identity,
+      client,
+      path,
+      current: Mutex::new(current),
+      retry: Default::default(),
+    })
+  }
+
+  /// The current enrollment, enrolling first if the agent is not enrolled yet.
+  pub(crate) async fn enrollment(&self) -> Option<Enrollment> {
+    let mut current = self.current.lock().await;
+    if current.is_none() {
+      *current = self.try_enroll().await;
+    }
+    current.clone()
+  }
+
+  /// The agent ID granted on enrollment, unknown while the agent is enrolling.
+  pub(crate) fn agent_id(&self) -> Option<String> {
+    let current = self.current.try_lock().ok()?;
+    current.as_ref().map(|enrollment| enrollment.agent_id().clone())
+  }
+
+  /// Enroll again since the server rejected the given enrollment, unless another report did it in
+  /// the meantime.
+  pub(crate) async fn reenroll(&self, rejected: &Enrollment) -> Option<Enrollment> {
+    let mut current = self.current.lock().await;
+    if current.as_ref() == Some(rejected) {
+      log::warn!(
+        "CMDB server rejected the credential of agent {}, enrolling again",
+        rejected.agent_id()
+      );
+      *current = self.try_enroll().await;
+    }
+    current.clone()
+  }
+
+  /// Enroll again for a new credential, keeping the current one if that fails.
+  pub(crate) async fn rotate(&self) -> Result<()> {
+    if self.config.enrollment().token().is_none() {
+      return Err(Error::new(ErrorKind::InvalidInput, "no enrollment token"));
+    }
+    let mut current = self.current.lock().await;
+    match self.enroll().await {
+      Some(enrollment) => {
+        *current = Some(enrollment);
+        Ok(())
+      }
+      None => Err(Error::new(
+        ErrorKind::Other,
+        "failed to enroll, keep the current credential",
+      )),
+    }
+  }
+
+  /// Enroll unless the last enrollment failed less than the backoff ago, the backoff doubling on
+  /// every failure.
+  async fn try_enroll(&self) -> Option<Enrollment> {
+    let config = self.config.enrollment();
+    config.token().as_ref()?;
+    let retry = *self.retry.lock().unwrap();
+    if retry.is_some_and(|(until, _)| Instant::now() < until) {
+      return None;
+    }
+
+    let enrollment = self.enroll().await;
+    *self.retry.lock().unwrap() = match enrollment {
+      Some(_) => None,
+      None => {
+        let delay = retry.map_or(*config.backoff(), |(_, delay)| {
+          (delay * 2).min(*config.max_backoff())
+        });
+        log::warn!("Enroll again in {:?}", delay);
+        Some((Instant::now() + delay, delay))
+      }
+    };
+    enrollment
+  }
+
+  async fn enroll(&self) -> Option<Enrollment> {
+    let token = self.config.enrollment().token().as_ref()?;
+
+    let mut request = EnrollmentRequest::default();
+    let machine = self.collect_machine_info().await;
+    let prepared = token.expose().and_then(|token| {
+      request.set_bootstrap_token(token);
+      request.set_machine(machine?);
+      Ok(())
+    });
+    if let Err(e) = prepared {
+      log::error!("Failed to prepare the enrollment: {}", e);
+      return None;
+    }
+    request.set_public_key(self.identity.as_ref().map(|identity| identity.public_key()));
+
+    // The agent enrolls with the first CMDB server among the targets, the granted credential going
+    // to every one of them without credentials of its own.
+    let target = self
+      .config
+      .targets()
+      .into_iter()
+      .find(|target| *target.sink() == SinkType::Http)?;
+    let urls = target.urls(self.config.enrollment().path());
+    let response = http::send(&urls, |url| self.client.post(url).json(&request)).await;
+    let enrollment = match response {
+      Ok(response) if response.status().is_success() => response.json::<Enrollment>().await,
+      Ok(response) => {
+        log::error!(
+          "Failed to enroll with CMDB server, who answers HTTP status: {}",
+          response.status()
+        );
+        return None;
+      }
+      Err(e) => {
+        log::error!("Failed to enroll with CMDB server: {}", e);
+        return None;
+      }
+    };
+    let enrollment = match enrollment {
+      Ok(enrollment) => enrollment,
+      Err(e) => {
+        log::error!("Failed to read the enrollment from CMDB server: {}", e);
+        return None;
+      }
+    };
+
+    let content = serde_json::to_string(&enrollment).expect("Enrollment is serializable");
+    if let Err(e) = write_private(&self.path, &content) {
+      log::warn!(
+        "Failed to keep the enrollment in {}, it is lost on restart: {}",
+        self.path.display(),
+        e
+      );
+    }
+    log::info!(
+      "Success to enroll with CMDB server as agent {}",
+      enrollment.agent_id()
+    );
+
+    Some(enrollment)
+  }
+
+  /// Collect the machine info on a blocking thread, given up on after the timeout of the inventory
+  /// job, so that the reports waiting for the enrollment are not stuck along with the collectors.
+  async fn collect_machine_info(&self) -> Result<MachineInfo> {
+    let timeout = *self.config.schedule().inventory().timeout();
+    let collecting = tokio::task::spawn_blocking(collect::get_machine_info);
+    match tokio::time::timeout(timeout, collecting).await {
+      Ok(collected) => collected.map_err(|e| Error::new(ErrorKind::Other, e))?,
+      Err(_) => Err(Error::new(
+        ErrorKind::TimedOut,
+        format!("collecting the machine info took over {:?}", timeout),
+      )),
+    }
+  }
+}
@@ -0,0 +1,110 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::Signer;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::support::fs::write_private;
+
+pub mod enroll;
+
+/// Name of the file holding the private key, in the state directory.
+const KEY_FILE: &str = "agent.key";
+
+/// The Ed25519 key pair of this very agent, generated on first start and kept in the state
+/// directory afterwards.
+///
+/// Unlike the HMAC keys shared by the fleet, the private key never leaves the machine: the server
+/// pins the public key when the agent enrolls, so a compromised node can only speak for itself.
+pub struct Identity {
+  key: ed25519_dalek::SigningKey,
+}
+
+impl Identity {
+  /// Load the key pair from the state directory, generating it on first start.
+  pub fn load_or_generate(state_dir: &Path) -> Result<Self> {
+    let path = state_dir.join(KEY_FILE);
+    match std::fs::read_to_string(&path) {
+      Ok(content) => {
+        let seed = STANDARD
+          .decode(content.trim())
+          .ok()
+          .and_then(|seed| <[u8; 32]>::try_from(seed).ok())
+          .ok_or_else(|| {
+            Error::new(
+              ErrorKind::InvalidData,
+              format!("{} is not a base64 Ed25519 private key", path.display()),
+            )
+          })?;
+        let identity = Self {
+          key: ed25519_dalek::SigningKey::from_bytes(&seed),
+        };
+        log::debug!(
+          "Loaded agent key pair from {}, public key {}",
+          path.display(),
+          identity.public_key()
+        );
+        Ok(identity)
+      }
+      Err(e) if e.kind() == ErrorKind::NotFound => {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        let identity = Self {
+          key: ed25519_dalek::SigningKey::from_bytes(&seed),
+        };
+        write_private(&path, &STANDARD.encode(seed))?;
+        log::info!(
+          "Generated agent key pair at {}, public key {}",
+          path.display(),
+          identity.public_key()
+        );
+        Ok(identity)
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  /// The public key, base64 encoded.
+  pub fn public_key(&self) -> String {
+    STANDARD.encode(self.key.verifying_key().to_bytes())
+  }
+
+  /// Sign the message, returning the base64 encoded signature.
+  pub fn sign(&self, message: &[u8]) -> String {
+    STANDARD.encode(self.key.sign(message).to_bytes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use ed25519_dalek::Signature;
+  use ed25519_dalek::Verifier;
+  use ed25519_dalek::VerifyingKey;
+
+  use super::*;
+
+  #[test]
+  fn test_load_or_generate() {
+    let dir = std::env::temp_dir().join("cmdb-agent-test-identity");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let generated = Identity::load_or_generate(&dir).unwrap();
+    let loaded = Identity::load_or_generate(&dir).unwrap();
+    assert_eq!(generated.public_key(), loaded.public_key());
+
+    let public_key: [u8; 32] = STANDARD.decode(loaded.public_key()).unwrap().try_into().unwrap();
+    let signature: [u8; 64] =
+      STANDARD.decode(generated.sign(b"report")).unwrap().try_into().unwrap();
+    assert!(VerifyingKey::from_bytes(&public_key)
+      .unwrap()
+      .verify(b"report", &Signature::from_bytes(&signature))
+      .is_ok());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,13 +1,22 @@
 use std::path::PathBuf;
 
 use clap::builder::Styles;
+use clap::builder::TypedValueParser;
 use clap::Parser;
+use clap::Subcommand;
+use support::clap_ext::KeyValueParser;
 use support::clap_ext::LogLevelValueParser;
 
 use crate::agent::Agent;
+use crate::config::discover::Discoverer;
+use crate::config::layer::Loader;
 
 pub mod agent;
+pub(crate) mod channel;
 pub(crate) mod collect;
+pub(crate) mod command;
+pub mod config;
+pub mod identity;
 pub mod schema;
 pub mod support;
 pub mod web;
@@ -23,13 +32,86 @@ struct Opts {
   /// Path to the configuration file.
   #[arg(
     long,
+    global = true,
     value_name = "CONFIG_FILE",
     default_value = "/etc/cmdb/agent.toml"
   )]
   config_file: PathBuf,
+  /// Directory of drop-in configuration files, `conf.d` next to the configuration file by default.
+  #[arg(long, global = true, value_name = "CONFIG_DIR")]
+  config_dir:  Option<PathBuf>,
+  /// Address the local web server listens on.
+  #[arg(long, global = true, value_name = "ADDR")]
+  addr:        Option<String>,
+  /// Base URL of the CMDB server, may be given multiple times.
+  #[arg(long = "endpoint", global = true, value_name = "URL")]
+  endpoints:   Vec<String>,
+  /// Override a configuration value by its dotted key, e.g. `http.timeout=30s`.
+  #[arg(
+    long = "set",
+    global = true,
+    value_name = "KEY=VALUE",
+    value_parser = KeyValueParser.map(|(key, value)| Override(key, value))
+  )]
+  overrides:   Vec<Override>,
   /// Set log level.
   #[arg(long, default_value_t = log::Level::Info, value_parser = LogLevelValueParser)]
   log_level:   log::Level,
+  #[command(subcommand)]
+  command:     Option<Command>,
+}
+
+/// A configuration value given by `--set`, which may be a secret.
+#[derive(Clone)]
+struct Override(String, String);
+
+impl std::fmt::Debug for Override {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}={}", self.0, config::secret::redact(&self.0, &self.1))
+  }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Inspect the configuration.
+  #[command(subcommand)]
+  Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+  /// Print the effective configuration and where each value comes from.
+  Show,
+  /// Validate the configuration, reporting every problem found. Exits with non-zero status if any.
+  Check {
+    /// Configuration file to check instead of `--config-file`.
+    #[arg(value_name = "FILE")]
+    file: Option<PathBuf>,
+  },
+}
+
+impl Opts {
+  fn loader(&self) -> Loader {
+    let file = match &self.command {
+      Some(Command::Config(ConfigCommand::Check { file: Some(file) })) => file,
+      _ => &self.config_file,
+    };
+
+    let mut loader = Loader::new(file);
+    if let Some(dir) = &self.config_dir {
+      loader = loader.dir(dir);
+    }
+    if let Some(addr) = &self.addr {
+      loader = loader.set("addr", addr);
+    }
+    if !self.endpoints.is_empty() {
+      loader = loader.set("server.endpoints", self.endpoints.join(","));
+    }
+    for Override(key, value) in &self.overrides {
+      loader = loader.set(key, value);
+    }
+    loader
+  }
 }
 
 #[actix_web::main]
@@ -40,11 +122,39 @@ async fn main() -> Result<(), std::io::Error> {
 
   let opts = Opts::parse();
 
-  let _ = simple_logger::init_with_level(opts.log_level);
+  // The logger takes every level, the max level being raised for a while by the CMDB server.
+  if simple_logger::SimpleLogger::new().init().is_ok() {
+    log::set_max_level(opts.log_level.to_level_filter());
+  }
   log::trace!("Current argument = {:?}", &opts);
 
-  let mut agent = Agent::new(opts.config_file);
-  let _ = agent.start().await?;
+  let loader = opts.loader();
+  match opts.command {
+    Some(Command::Config(ConfigCommand::Show)) => match loader.load() {
+      Ok(layered) => print!("{}", layered.render()),
+      Err(e) => {
+        eprintln!("{}", e);
+        std::process::exit(1);
+      }
+    },
+    Some(Command::Config(ConfigCommand::Check { .. })) => match loader.load() {
+      Ok(_) => println!("{}: OK", loader.file().display()),
+      Err(e) => {
+        for diagnostic in &e.diagnostics {
+          eprintln!("{}", diagnostic);
+        }
+        std::process::exit(1);
+      }
+    },
+    None => {
+      Discoverer::new(loader.clone()).discover().await;
+      let mut agent = Agent::new(loader).unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
+      });
+      let _ = agent.start().await?;
+    }
+  }
 
   Ok(())
 }
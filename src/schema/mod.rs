@@ -1,3 +1,6 @@
+use std::time::Duration;
+use std::time::SystemTime;
+
 use getset::CopyGetters;
 use getset::Getters;
 use getset::MutGetters;
@@ -24,6 +27,26 @@ pub struct MachineInfo {
   networks:      Vec<Network>,
 }
 
+impl MachineInfo {
+  /// Sections identifying the machine, which every target receives.
+  pub const IDENTITY_SECTIONS: &'static [&'static str] = &["hostname", "serialNumber"];
+
+  /// Names of the top-level sections, as serialized.
+  pub fn sections() -> Vec<String> {
+    match serde_json::to_value(MachineInfo::default()) {
+      Ok(serde_json::Value::Object(sections)) => sections.keys().cloned().collect(),
+      _ => vec![],
+    }
+  }
+
+  /// Names of the sections which can be left out of the collection, all but the identity ones.
+  pub fn collectors() -> Vec<String> {
+    let mut sections = Self::sections();
+    sections.retain(|section| !Self::IDENTITY_SECTIONS.contains(&section.as_str()));
+    sections
+  }
+}
+
 #[derive(
   Clone, Debug, Default, Serialize, Deserialize, Getters, Setters, MutGetters, CopyGetters,
 )]
@@ -102,3 +125,228 @@ pub struct Network {
   #[getset(get = "pub", set = "pub", get_mut = "pub")]
   mac_address: String,
 }
+
+/// Sent by the agent on every heartbeat, for the server to tell which agent is alive and how it
+/// fares.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Getters, Setters)]
+#[serde(rename_all = "camelCase")]
+pub struct Heartbeat {
+  /// Granted on enrollment, unset until the agent is enrolled.
+  #[getset(get = "pub", set = "pub")]
+  agent_id:        Option<String>,
+  #[getset(get = "pub", set = "pub")]
+  hostname:        String,
+  /// Version of the agent.
+  #[getset(get = "pub", set = "pub")]
+  version:         String,
+  /// Seconds since the agent started.
+  #[getset(get = "pub", set = "pub")]
+  uptime:          u64,
+  #[getset(get = "pub", set = "pub")]
+  state:           AgentState,
+  #[serde(with = "humantime_serde")]
+  #[getset(get = "pub", set = "pub")]
+  timestamp:       Option<SystemTime>,
+  #[getset(get = "pub", set = "pub")]
+  last_collection: Option<LastRun>,
+  /// The last report of the inventory.
+  #[getset(get = "pub", set = "pub")]
+  last_report:     Option<LastRun>,
+  /// Content hash of the last collected inventory, for the server to tell whether it is in sync.
+  #[getset(get = "pub", set = "pub")]
+  inventory_hash:  Option<String>,
+  /// Where the agent runs, if it runs in Kubernetes.
+  #[getset(get = "pub", set = "pub")]
+  kubernetes:      Option<Kubernetes>,
+  /// Outcome of the commands run since the last heartbeat.
+  #[getset(get = "pub", set = "pub")]
+  command_acks:    Vec<CommandAck>,
+}
+
+/// Answered by the server to a heartbeat, all of it optional.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Getters)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HeartbeatResponse {
+  /// Commands for the agent to run, each of them a signed [`Command`], kept as is to verify the
+  /// signature.
+  #[getset(get = "pub")]
+  commands: Vec<serde_json::Value>,
+}
+
+/// A command from the server, signed with the HMAC key named by `keyId`.
+///
+/// The signature is the one of the command as compact JSON with sorted keys, without the
+/// `signature` itself, formatted as the `X-CMDB-Signature` header, e.g. `SHA256=<hex>`. It covers
+/// `expiresAt`, after which the command is rejected, so that a captured command can not be replayed
+/// for long, even after the agent restarts and forgets the commands it ran.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct Command {
+  /// Unique, for the command to be run once and acknowledged.
+  #[getset(get = "pub")]
+  id:         String,
+  #[serde(flatten)]
+  #[getset(get = "pub")]
+  action:     Action,
+  /// Time after which the command is rejected, at most an hour after it is received.
+  #[serde(with = "humantime_serde")]
+  #[getset(get = "pub")]
+  expires_at: SystemTime,
+  #[getset(get = "pub")]
+  key_id:     String,
+  #[getset(get = "pub")]
+  signature:  String,
+}
+
+/// What a command asks the agent to do, by `type`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+  /// Collect and report the inventory now.
+  CollectNow,
+  /// Run the job on another schedule, a cron expression with seconds or an interval such as `6h`.
+  SetSchedule {
+    job:      Job,
+    schedule: String,
+  },
+  /// Collect the sections of the inventory, or leave them out.
+  EnableCollectors {
+    collectors: Vec<String>,
+  },
+  DisableCollectors {
+    collectors: Vec<String>,
+  },
+  /// Enroll again for a new credential, keeping the current one on failure.
+  RotateCredential,
+  /// Log at the level, e.g. `debug`, for the duration, then at the level the agent started with.
+  SetLogLevel {
+    level:    String,
+    #[serde(with = "humantime_serde")]
+    duration: Duration,
+  },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Job {
+  Heartbeat,
+  Inventory,
+}
+
+/// Outcome of a command, sent along with the next heartbeat.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandAck {
+  #[getset(get = "pub")]
+  id:        String,
+  #[getset(get = "pub")]
+  status:    CommandStatus,
+  #[getset(get = "pub")]
+  error:     Option<String>,
+  #[serde(with = "humantime_serde")]
+  #[getset(get = "pub")]
+  timestamp: SystemTime,
+}
+
+impl CommandAck {
+  pub fn new(id: String, status: CommandStatus, error: Option<String>) -> Self {
+    Self {
+      id,
+      status,
+      error,
+      timestamp: SystemTime::now(),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+  Done,
+  Failed,
+  /// Not run, being unsigned, badly signed or unknown.
+  Rejected,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentState {
+  #[default]
+  Ready,
+  Start,
+}
+
+/// When a collection or a report last ran, and how it turned out.
+#[derive(Clone, Debug, Serialize, Deserialize, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct LastRun {
+  #[serde(with = "humantime_serde")]
+  #[getset(get = "pub")]
+  timestamp: SystemTime,
+  #[getset(get = "pub")]
+  status:    RunStatus,
+  #[getset(get = "pub")]
+  error:     Option<String>,
+}
+
+impl LastRun {
+  pub fn new(status: RunStatus, error: Option<String>) -> Self {
+    Self {
+      timestamp: SystemTime::now(),
+      status,
+      error,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+  Success,
+  /// The report is queued in the outbox, to be delivered later.
+  Queued,
+  Failed,
+}
+
+/// The pod of the agent, as injected by the DaemonSet.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Getters, Setters)]
+#[serde(rename_all = "camelCase")]
+pub struct Kubernetes {
+  #[getset(get = "pub", set = "pub")]
+  namespace: Option<String>,
+  #[getset(get = "pub", set = "pub")]
+  pod_name:  Option<String>,
+  #[getset(get = "pub", set = "pub")]
+  node_name: Option<String>,
+}
+
+/// Sent by the agent to enroll with the CMDB server.
+#[derive(Clone, Default, Serialize, Getters, Setters)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrollmentRequest {
+  #[getset(get = "pub", set = "pub")]
+  bootstrap_token: String,
+  /// Ed25519 public key of the agent, base64 encoded, for the server to pin.
+  #[getset(get = "pub", set = "pub")]
+  public_key:      Option<String>,
+  #[getset(get = "pub", set = "pub")]
+  machine:         MachineInfo,
+}
+
+/// Granted by the CMDB server on enrollment.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+  #[getset(get = "pub")]
+  agent_id:   String,
+  #[getset(get = "pub")]
+  credential: String,
+}
+
+impl std::fmt::Debug for Enrollment {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Enrollment")
+      .field("agent_id", &self.agent_id)
+      .finish_non_exhaustive()
+  }
+}
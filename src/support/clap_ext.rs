@@ -23,3 +23,28 @@ impl TypedValueParser for LogLevelValueParser {
     log::Level::from_str(level).map_err(|e| Error::raw(ErrorKind::InvalidValue, e.to_string()))
   }
 }
+
+/// Parse `KEY=VALUE` pairs.
+#[derive(Clone)]
+pub struct KeyValueParser;
+
+impl TypedValueParser for KeyValueParser {
+  type Value = (String, String);
+
+  fn parse_ref(
+    &self,
+    _cmd: &Command,
+    _arg: Option<&Arg>,
+    value: &OsStr,
+  ) -> Result<Self::Value, Error> {
+    let pair = value.to_str().ok_or(Error::new(ErrorKind::InvalidUtf8))?;
+    pair
+      .split_once('=')
+      .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+      .filter(|(key, _)| !key.is_empty())
+      .ok_or(Error::raw(
+        ErrorKind::InvalidValue,
+        format!("Expected KEY=VALUE, got `{}`\n", pair),
+      ))
+  }
+}
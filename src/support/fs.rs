@@ -0,0 +1,24 @@
+use std::fs::OpenOptions;
+use std::io::Result;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Write a file readable by its owner only, replacing it at once so that a crash never leaves a
+/// truncated file behind.
+pub fn write_private(path: &Path, content: &str) -> Result<()> {
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+
+  let temp = path.with_extension("tmp");
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(&temp)?;
+  writeln!(file, "{}", content)?;
+  file.sync_all()?;
+  std::fs::rename(temp, path)
+}
@@ -1,7 +1,29 @@
 use std::path::Path;
 
+use crate::schema::Kubernetes;
+
 const KUBERNETES_SERVICEACCOUNT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const KUBERNETES_NAMESPACE_KEY: &str = "K8S_NAMESPACE";
+const KUBERNETES_POD_NAME_KEY: &str = "K8S_POD_NAME";
+pub const KUBERNETES_NODE_NAME_KEY: &str = "K8S_NODE_NAME";
 
 pub fn is_under_kubernetes() -> bool {
   Path::new(KUBERNETES_SERVICEACCOUNT_PATH).exists()
 }
+
+/// Get the pod of the agent from the environment variables injected by the DaemonSet, if any of
+/// them is set.
+pub fn get_kubernetes() -> Option<Kubernetes> {
+  let namespace = std::env::var(KUBERNETES_NAMESPACE_KEY).ok();
+  let pod_name = std::env::var(KUBERNETES_POD_NAME_KEY).ok();
+  let node_name = std::env::var(KUBERNETES_NODE_NAME_KEY).ok();
+  if namespace.is_none() && pod_name.is_none() && node_name.is_none() {
+    return None;
+  }
+
+  let mut kubernetes = Kubernetes::default();
+  kubernetes.set_namespace(namespace);
+  kubernetes.set_pod_name(pod_name);
+  kubernetes.set_node_name(node_name);
+  Some(kubernetes)
+}
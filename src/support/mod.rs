@@ -1,2 +1,3 @@
 pub mod clap_ext;
+pub mod fs;
 pub mod kube;
@@ -1,7 +1,22 @@
 use actix_web::get;
+use actix_web::web;
 use actix_web::Responder;
 
+use crate::collect::state::ReportState;
+
 #[get("/healthz")]
 async fn health_handler() -> impl Responder {
   "OK"
 }
+
+/// Delivery status of each target the agent reported to, by name.
+#[get("/targets")]
+async fn targets_handler(reports: web::Data<ReportState>) -> impl Responder {
+  web::Json(reports.targets())
+}
+
+/// Runs of each scheduled job, with the ones skipped or timed out, by name.
+#[get("/jobs")]
+async fn jobs_handler(reports: web::Data<ReportState>) -> impl Responder {
+  web::Json(reports.jobs())
+}